@@ -0,0 +1,134 @@
+use std::{
+    env,
+    io::{self, BufRead, Write},
+    net::IpAddr,
+    process::ExitCode,
+};
+
+use shuttlings_cch24::cipher::{Cipher, CipherKind};
+
+const USAGE: &str = "\
+usage: cch-ip <dest|key> [--cipher add|xor|rotate|permute] [FROM KEY|TO]
+
+Without addresses, pairs are read from stdin, one whitespace separated pair per line.
+v4 pairs default to the add cipher and v6 pairs to the xor cipher.";
+
+#[derive(Clone, Copy)]
+enum Operation {
+    Dest,
+    Key,
+}
+
+fn transform(
+    operation: Operation,
+    cipher: Option<CipherKind>,
+    line: &str,
+) -> Result<IpAddr, String> {
+    let mut parts = line.split_whitespace();
+    let (Some(from), Some(other), None) = (parts.next(), parts.next(), parts.next()) else {
+        return Err("expected two addresses".to_owned());
+    };
+    let from = from.parse::<IpAddr>().map_err(|e| format!("{from}: {e}"))?;
+    let other = other
+        .parse::<IpAddr>()
+        .map_err(|e| format!("{other}: {e}"))?;
+
+    Ok(match (from, other) {
+        (IpAddr::V4(from), IpAddr::V4(other)) => {
+            let cipher = cipher.unwrap_or(CipherKind::Add);
+            match operation {
+                Operation::Dest => cipher.encrypt_v4(from, other),
+                Operation::Key => cipher.derive_key_v4(from, other),
+            }
+            .into()
+        }
+        (IpAddr::V6(from), IpAddr::V6(other)) => {
+            let cipher = cipher.unwrap_or(CipherKind::Xor);
+            match operation {
+                Operation::Dest => cipher.encrypt_v6(from, other),
+                Operation::Key => cipher.derive_key_v6(from, other),
+            }
+            .into()
+        }
+        _ => return Err("addresses are of different families".to_owned()),
+    })
+}
+
+fn main() -> ExitCode {
+    let mut args = env::args().skip(1);
+    let operation = match args.next().as_deref() {
+        Some("dest") => Operation::Dest,
+        Some("key") => Operation::Key,
+        _ => {
+            eprintln!("{USAGE}");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let mut cipher = None;
+    let mut addresses = vec![];
+    while let Some(arg) = args.next() {
+        if arg != "--cipher" {
+            addresses.push(arg);
+            continue;
+        }
+        match args.next().map(|name| name.parse::<CipherKind>()) {
+            Some(Ok(kind)) => cipher = Some(kind),
+            Some(Err(e)) => {
+                eprintln!("{e}");
+                return ExitCode::FAILURE;
+            }
+            None => {
+                eprintln!("{USAGE}");
+                return ExitCode::FAILURE;
+            }
+        }
+    }
+
+    if !addresses.is_empty() {
+        return match transform(operation, cipher, &addresses.join(" ")) {
+            Ok(address) => {
+                println!("{address}");
+                ExitCode::SUCCESS
+            }
+            Err(e) => {
+                eprintln!("{e}");
+                ExitCode::FAILURE
+            }
+        };
+    }
+
+    // Failed and blank lines print an empty line, so output lines match input lines
+    let mut failed = false;
+    let mut stdout = io::stdout().lock();
+    for (number, line) in io::stdin().lock().lines().enumerate() {
+        let line = match line {
+            Ok(line) => line,
+            Err(e) => {
+                eprintln!("{e}");
+                return ExitCode::FAILURE;
+            }
+        };
+        let output = if line.trim().is_empty() {
+            String::new()
+        } else {
+            match transform(operation, cipher, &line) {
+                Ok(address) => address.to_string(),
+                Err(e) => {
+                    eprintln!("line {}: {e}", number + 1);
+                    failed = true;
+                    String::new()
+                }
+            }
+        };
+        if writeln!(stdout, "{output}").is_err() {
+            return ExitCode::FAILURE;
+        }
+    }
+
+    if failed {
+        ExitCode::FAILURE
+    } else {
+        ExitCode::SUCCESS
+    }
+}
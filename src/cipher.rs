@@ -0,0 +1,348 @@
+use std::{
+    error::Error,
+    fmt,
+    net::{Ipv4Addr, Ipv6Addr},
+    ops::BitXor,
+    str::FromStr,
+};
+
+use serde::{Deserialize, Serialize};
+
+/// An octet of a v4 or MAC address, or a segment of a v6 address.
+pub trait Unit: Copy + BitXor<Output = Self> {
+    const BITS: u32;
+    const ONE: Self;
+
+    fn wrapping_add(self, rhs: Self) -> Self;
+    fn wrapping_sub(self, rhs: Self) -> Self;
+    fn wrapping_mul(self, rhs: Self) -> Self;
+    fn rotate_left(self, n: u32) -> Self;
+    fn rotate_right(self, n: u32) -> Self;
+    // The multiplicative inverse modulo 2^BITS, only odd units have one
+    fn inverse(self) -> Self;
+}
+
+macro_rules! impl_unit {
+    ($unit:ty) => {
+        impl Unit for $unit {
+            const BITS: u32 = <$unit>::BITS;
+            const ONE: Self = 1;
+
+            fn wrapping_add(self, rhs: Self) -> Self {
+                <$unit>::wrapping_add(self, rhs)
+            }
+
+            fn wrapping_sub(self, rhs: Self) -> Self {
+                <$unit>::wrapping_sub(self, rhs)
+            }
+
+            fn wrapping_mul(self, rhs: Self) -> Self {
+                <$unit>::wrapping_mul(self, rhs)
+            }
+
+            fn rotate_left(self, n: u32) -> Self {
+                <$unit>::rotate_left(self, n)
+            }
+
+            fn rotate_right(self, n: u32) -> Self {
+                <$unit>::rotate_right(self, n)
+            }
+
+            // Newton's iteration: an odd unit is its own inverse in the low 3 bits, and every
+            // step doubles the bits that are right, 3 steps cover 16 bits
+            fn inverse(self) -> Self {
+                let mut inverse = self;
+                for _ in 0..3 {
+                    let step = (2 as $unit).wrapping_sub(self.wrapping_mul(inverse));
+                    inverse = inverse.wrapping_mul(step);
+                }
+                inverse
+            }
+        }
+    };
+}
+
+impl_unit!(u8);
+impl_unit!(u16);
+
+fn zip_units<U: Unit, const N: usize>(a: [U; N], b: [U; N], f: impl Fn(U, U) -> U) -> [U; N] {
+    std::array::from_fn(|i| f(a[i], b[i]))
+}
+
+/// A scheme that encrypts an address with a key, unit by unit.
+///
+/// `derive_key` must undo `encrypt`, so that `derive_key(from, encrypt(from, key)) == key` for
+/// every `from` and `key`.
+pub trait Cipher {
+    fn encrypt<U: Unit>(&self, from: U, key: U) -> U;
+    fn derive_key<U: Unit>(&self, from: U, to: U) -> U;
+
+    fn encrypt_v4(&self, from: Ipv4Addr, key: Ipv4Addr) -> Ipv4Addr {
+        zip_units(from.octets(), key.octets(), |from, key| {
+            self.encrypt(from, key)
+        })
+        .into()
+    }
+
+    fn derive_key_v4(&self, from: Ipv4Addr, to: Ipv4Addr) -> Ipv4Addr {
+        zip_units(from.octets(), to.octets(), |from, to| {
+            self.derive_key(from, to)
+        })
+        .into()
+    }
+
+    fn encrypt_v6(&self, from: Ipv6Addr, key: Ipv6Addr) -> Ipv6Addr {
+        zip_units(from.segments(), key.segments(), |from, key| {
+            self.encrypt(from, key)
+        })
+        .into()
+    }
+
+    fn derive_key_v6(&self, from: Ipv6Addr, to: Ipv6Addr) -> Ipv6Addr {
+        zip_units(from.segments(), to.segments(), |from, to| {
+            self.derive_key(from, to)
+        })
+        .into()
+    }
+
+    fn encrypt_mac(&self, from: Mac, key: Mac) -> Mac {
+        Mac(zip_units(from.0, key.0, |from, key| {
+            self.encrypt(from, key)
+        }))
+    }
+
+    fn derive_key_mac(&self, from: Mac, to: Mac) -> Mac {
+        Mac(zip_units(from.0, to.0, |from, to| {
+            self.derive_key(from, to)
+        }))
+    }
+}
+
+/// Wrapping addition, the day 2 cipher for v4 addresses.
+pub struct AddSub;
+
+impl Cipher for AddSub {
+    fn encrypt<U: Unit>(&self, from: U, key: U) -> U {
+        from.wrapping_add(key)
+    }
+
+    fn derive_key<U: Unit>(&self, from: U, to: U) -> U {
+        to.wrapping_sub(from)
+    }
+}
+
+/// XOR, the day 2 cipher for v6 addresses.
+pub struct Xor;
+
+// XOR is its own inverse, the key is recovered the same way the address is encrypted
+impl Cipher for Xor {
+    fn encrypt<U: Unit>(&self, from: U, key: U) -> U {
+        from ^ key
+    }
+
+    fn derive_key<U: Unit>(&self, from: U, to: U) -> U {
+        from ^ to
+    }
+}
+
+/// Swaps the halves of each unit of `from` before adding the key.
+pub struct Rotate;
+
+impl Cipher for Rotate {
+    fn encrypt<U: Unit>(&self, from: U, key: U) -> U {
+        from.rotate_left(U::BITS / 2).wrapping_add(key)
+    }
+
+    fn derive_key<U: Unit>(&self, from: U, to: U) -> U {
+        to.wrapping_sub(from.rotate_left(U::BITS / 2))
+    }
+}
+
+/// Runs each unit of `from` through an affine permutation picked by the key unit:
+/// `from * (2 * key + 1) + key`.
+///
+/// The multiplier is odd, so every key permutes the units. Read as
+/// `from + key * (2 * from + 1)`, the same holds for the key, which is how it's recovered.
+pub struct Permute;
+
+fn odd<U: Unit>(unit: U) -> U {
+    unit.wrapping_add(unit).wrapping_add(U::ONE)
+}
+
+impl Cipher for Permute {
+    fn encrypt<U: Unit>(&self, from: U, key: U) -> U {
+        from.wrapping_mul(odd(key)).wrapping_add(key)
+    }
+
+    fn derive_key<U: Unit>(&self, from: U, to: U) -> U {
+        to.wrapping_sub(from).wrapping_mul(odd(from).inverse())
+    }
+}
+
+/// Picks one of the ciphers at runtime.
+#[derive(Debug, Clone, Copy, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CipherKind {
+    Add,
+    Xor,
+    Rotate,
+    Permute,
+}
+
+impl CipherKind {
+    /// Every cipher, in declaration order.
+    pub const ALL: [Self; 4] = [Self::Add, Self::Xor, Self::Rotate, Self::Permute];
+}
+
+impl Cipher for CipherKind {
+    fn encrypt<U: Unit>(&self, from: U, key: U) -> U {
+        match self {
+            Self::Add => AddSub.encrypt(from, key),
+            Self::Xor => Xor.encrypt(from, key),
+            Self::Rotate => Rotate.encrypt(from, key),
+            Self::Permute => Permute.encrypt(from, key),
+        }
+    }
+
+    fn derive_key<U: Unit>(&self, from: U, to: U) -> U {
+        match self {
+            Self::Add => AddSub.derive_key(from, to),
+            Self::Xor => Xor.derive_key(from, to),
+            Self::Rotate => Rotate.derive_key(from, to),
+            Self::Permute => Permute.derive_key(from, to),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct UnknownCipher(pub String);
+
+impl fmt::Display for UnknownCipher {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "unknown cipher: {}", self.0)
+    }
+}
+
+impl Error for UnknownCipher {}
+
+impl FromStr for CipherKind {
+    type Err = UnknownCipher;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "add" => Ok(Self::Add),
+            "xor" => Ok(Self::Xor),
+            "rotate" => Ok(Self::Rotate),
+            "permute" => Ok(Self::Permute),
+            _ => Err(UnknownCipher(s.to_owned())),
+        }
+    }
+}
+
+/// A 48-bit MAC address.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Mac(pub [u8; 6]);
+
+impl fmt::Display for Mac {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let [a, b, c, d, e, g] = self.0;
+        write!(f, "{a:02x}:{b:02x}:{c:02x}:{d:02x}:{e:02x}:{g:02x}")
+    }
+}
+
+impl Mac {
+    /// The modified EUI-64 interface identifier: `ff:fe` goes in the middle and the
+    /// universal/local bit is flipped.
+    pub fn interface_id(self) -> u64 {
+        let [a, b, c, d, e, g] = self.0;
+        u64::from_be_bytes([a ^ 0x02, b, c, 0xff, 0xfe, d, e, g])
+    }
+}
+
+/// Encrypts `from` by adding `key` to it octet by octet.
+pub fn encrypt_v4(from: Ipv4Addr, key: Ipv4Addr) -> Ipv4Addr {
+    AddSub.encrypt_v4(from, key)
+}
+
+/// Recovers the key that [`encrypt_v4`] used to turn `from` into `to`.
+pub fn derive_key_v4(from: Ipv4Addr, to: Ipv4Addr) -> Ipv4Addr {
+    AddSub.derive_key_v4(from, to)
+}
+
+/// Encrypts `from` by XORing it with `key` segment by segment.
+pub fn encrypt_v6(from: Ipv6Addr, key: Ipv6Addr) -> Ipv6Addr {
+    Xor.encrypt_v6(from, key)
+}
+
+/// Recovers the key that [`encrypt_v6`] used to turn `from` into `to`.
+pub fn derive_key_v6(from: Ipv6Addr, to: Ipv6Addr) -> Ipv6Addr {
+    Xor.derive_key_v6(from, to)
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+
+    use super::*;
+
+    fn cipher() -> impl Strategy<Value = CipherKind> {
+        proptest::sample::select(CipherKind::ALL.to_vec())
+    }
+
+    proptest! {
+        #[test]
+        fn v4_round_trip(cipher in cipher(), from: u32, key: u32) {
+            let (from, key) = (Ipv4Addr::from(from), Ipv4Addr::from(key));
+            let to = cipher.encrypt_v4(from, key);
+            prop_assert_eq!(cipher.derive_key_v4(from, to), key);
+        }
+
+        #[test]
+        fn v6_round_trip(cipher in cipher(), from: u128, key: u128) {
+            let (from, key) = (Ipv6Addr::from(from), Ipv6Addr::from(key));
+            let to = cipher.encrypt_v6(from, key);
+            prop_assert_eq!(cipher.derive_key_v6(from, to), key);
+        }
+
+        #[test]
+        fn mac_round_trip(cipher in cipher(), from: [u8; 6], key: [u8; 6]) {
+            let (from, key) = (Mac(from), Mac(key));
+            let to = cipher.encrypt_mac(from, key);
+            prop_assert_eq!(cipher.derive_key_mac(from, to), key);
+        }
+
+        #[test]
+        fn octet_round_trip(cipher in cipher(), from: u8, key: u8) {
+            prop_assert_eq!(cipher.derive_key(from, cipher.encrypt(from, key)), key);
+        }
+
+        #[test]
+        fn segment_round_trip(cipher in cipher(), from: u16, key: u16) {
+            prop_assert_eq!(cipher.derive_key(from, cipher.encrypt(from, key)), key);
+        }
+    }
+
+    #[test]
+    fn permute_depends_on_the_key() {
+        let encrypted = |key: u8| (0..=u8::MAX).map(move |from| Permute.encrypt(from, key));
+        for key in [0, 1, 0x80, 0xff] {
+            let mut units = encrypted(key).collect::<Vec<_>>();
+            units.sort_unstable();
+            units.dedup();
+            assert_eq!(units.len(), 256, "key {key} doesn't permute the octets");
+        }
+        assert!(encrypted(1).ne(encrypted(2)));
+        assert_eq!(Permute.encrypt(3u8, 5), 38);
+    }
+
+    #[test]
+    fn v4_wraps_around() {
+        let max = Ipv4Addr::new(255, 255, 255, 255);
+        let one = Ipv4Addr::new(1, 1, 1, 1);
+
+        assert_eq!(encrypt_v4(max, one), Ipv4Addr::UNSPECIFIED);
+        assert_eq!(encrypt_v4(max, max), Ipv4Addr::new(254, 254, 254, 254));
+        assert_eq!(derive_key_v4(one, Ipv4Addr::UNSPECIFIED), max);
+        assert_eq!(derive_key_v4(max, one), Ipv4Addr::new(2, 2, 2, 2));
+    }
+}
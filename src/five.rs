@@ -1,85 +1,1366 @@
+use std::sync::Arc;
+
 use poem::{
     handler,
-    http::StatusCode,
+    http::{header::ACCEPT_ENCODING, StatusCode},
+    middleware::Compression,
     post,
-    web::{headers::ContentType, TypedHeader},
-    Response, Result, Route,
+    web::{
+        headers::ContentType, Accept, Data, Html as HtmlBody, Json, Multipart, Query, TypedHeader,
+    },
+    Body, Endpoint, EndpointExt, Error, FromRequest, IntoResponse, Request, RequestBody, Response,
+    Result, Route,
 };
-use serde::Deserialize;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::{json, Value};
 use serde_with::serde_as;
 
+use dependencies::dependency_tables;
+use diagnostics::{join_path, locate, Diagnostic};
+pub use invoice::Catalogue;
+pub use policy::Policy;
+use policy::RuleOutcome;
+
+mod dependencies;
+mod diagnostics;
+mod invoice;
+mod policy;
+
+// A workspace root and its members, as an alternative to multipart uploads
+const BUNDLE: &str = "application/vnd.cch.workspace+json";
+// Asks `/5/manifest` for a priced invoice instead of a receipt
+const INVOICE: &str = "application/vnd.cch.invoice+json";
+
 #[derive(Debug, Deserialize)]
 struct Metadata {
     #[serde(default)]
     orders: Vec<Order>,
 }
 
+// Orders are checked one by one, so that every bad entry gets reported
+#[derive(Debug, Clone, Deserialize)]
+pub struct RawMetadata {
+    orders: Option<Vec<Value>>,
+    #[serde(flatten)]
+    rest: serde_json::Map<String, Value>,
+}
+
 #[serde_as]
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Serialize)]
 struct Order {
+    // Where the order sat in the manifest, before aggregation moved it
+    #[serde(skip)]
+    index: usize,
     item: String,
     #[serde_as(deserialize_as = "serde_with::DefaultOnError")]
     #[serde(default)]
     quantity: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    price: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    currency: Option<String>,
+}
+
+// An order left out of the receipt because its quantity is missing or unusable
+#[derive(Debug, Serialize)]
+struct Skipped {
+    index: usize,
+    item: String,
+    reason: String,
+}
+
+#[derive(Debug, Serialize)]
+struct PackageSummary {
+    name: String,
+    version: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Receipt {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    source: Option<String>,
+    orders: Vec<Order>,
+    total_quantity: u64,
+    package: PackageSummary,
+    skipped: Vec<Skipped>,
+    policy: Vec<RuleOutcome>,
+}
+
+impl Metadata {
+    // Sums the quantities of orders for the same item, keeping the first order's position
+    fn aggregate(self) -> Self {
+        let mut orders: Vec<Order> = vec![];
+        for order in self.orders {
+            // Orders for the same item at different prices stay on their own lines
+            let same = |merged: &&mut Order| {
+                merged.item == order.item
+                    && merged.price == order.price
+                    && merged.currency == order.currency
+            };
+            match orders.iter_mut().find(same) {
+                Some(merged) => {
+                    merged.quantity = merged
+                        .quantity
+                        .zip(order.quantity)
+                        .map(|(a, b)| a.saturating_add(b))
+                        .or(merged.quantity)
+                        .or(order.quantity);
+                }
+                None => orders.push(order),
+            }
+        }
+        Self { orders }
+    }
+}
+
+fn aggregate_by_default() -> bool {
+    true
+}
+
+#[derive(Deserialize)]
+struct Options {
+    #[serde(default)]
+    strict: bool,
+    #[serde(default = "aggregate_by_default")]
+    aggregate: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    Json,
+    Toml,
+    Yaml,
+}
+
+// Fields that members of a workspace take from its root
+#[derive(Debug, Default)]
+pub struct Inherited {
+    package: Option<cargo_manifest::WorkspacePackage>,
+    metadata: Option<RawMetadata>,
+}
+
+// A package that passed every check
+#[derive(Debug)]
+struct Checked {
+    package: PackageSummary,
+    // Where the orders were read from, `package.metadata` or `workspace.metadata`
+    prefix: &'static str,
+    metadata: Option<Metadata>,
+    skipped: Vec<Skipped>,
+    policy: Vec<RuleOutcome>,
+}
+
+impl Checked {
+    fn receipt(self, aggregate: bool) -> Receipt {
+        let mut metadata = self.metadata.unwrap_or(Metadata { orders: vec![] });
+        if aggregate {
+            metadata = metadata.aggregate();
+        }
+        Receipt {
+            source: None,
+            total_quantity: metadata
+                .orders
+                .iter()
+                .filter_map(|order| order.quantity)
+                .map(u64::from)
+                .sum(),
+            orders: metadata.orders,
+            package: self.package,
+            skipped: self.skipped,
+            policy: self.policy,
+        }
+    }
+
+    fn text(self) -> Option<String> {
+        let order_receipt = self
+            .metadata?
+            .orders
+            .iter()
+            .filter_map(|Order { item, quantity, .. }| {
+                quantity.map(|quantity| format!("{item}: {quantity}"))
+            })
+            .collect::<Vec<String>>();
+
+        (!order_receipt.is_empty()).then(|| order_receipt.join("\n"))
+    }
+}
+
+// A package that failed a check, with how it did on every rule of the policy
+#[derive(Debug)]
+struct Rejected {
+    diagnostics: Vec<Diagnostic>,
+    policy: Vec<RuleOutcome>,
+}
+
+impl Rejected {
+    fn within(self, source: &str) -> Self {
+        Self {
+            diagnostics: self
+                .diagnostics
+                .into_iter()
+                .map(|d| d.within(source))
+                .collect(),
+            policy: self.policy.into_iter().map(|o| o.within(source)).collect(),
+        }
+    }
+
+    fn locate(self, data: &str, prefix: &str) -> Self {
+        Self {
+            diagnostics: locate(self.diagnostics, data, prefix),
+            ..self
+        }
+    }
+}
+
+// A document uploaded as part of a workspace, named after its multipart field or bundle position
+struct Member {
+    source: String,
+    format: Format,
+    document: cargo_manifest::Manifest<RawMetadata>,
+    // The text the document was read from, the whole bundle for bundle members
+    data: Arc<str>,
+    prefix: String,
+}
+
+#[derive(Deserialize)]
+struct Bundle {
+    root: Option<cargo_manifest::Manifest<RawMetadata>>,
+    #[serde(default)]
+    members: Vec<cargo_manifest::Manifest<RawMetadata>>,
+}
+
+impl Format {
+    fn mime(self) -> &'static str {
+        match self {
+            Self::Json => "application/json",
+            Self::Toml => "application/toml",
+            Self::Yaml => "application/yaml",
+        }
+    }
+
+    fn from_mime(mime: &str) -> Option<Self> {
+        match media_type(mime)?.as_str() {
+            "application/json" | "text/json" => Some(Self::Json),
+            "application/toml" | "application/x-toml" | "text/toml" | "text/x-toml" => {
+                Some(Self::Toml)
+            }
+            "application/yaml" | "application/x-yaml" | "text/yaml" | "text/x-yaml" => {
+                Some(Self::Yaml)
+            }
+            _ => None,
+        }
+    }
+
+    fn from_file_name(name: &str) -> Option<Self> {
+        match name.rsplit_once('.')?.1.to_lowercase().as_str() {
+            "json" => Some(Self::Json),
+            "toml" => Some(Self::Toml),
+            "yaml" | "yml" => Some(Self::Yaml),
+            _ => None,
+        }
+    }
+
+    fn parse<T: DeserializeOwned>(self, data: &str) -> Result<T, Diagnostic> {
+        match self {
+            Self::Json => {
+                let mut deserializer = serde_json::Deserializer::from_str(data);
+                let parsed = serde_path_to_error::deserialize(&mut deserializer)
+                    .map_err(|e| (e.path().to_string(), e.into_inner()))
+                    .and_then(|parsed| {
+                        deserializer.end().map_err(|e| (".".to_owned(), e))?;
+                        Ok(parsed)
+                    });
+                parsed.map_err(|(path, e)| {
+                    Diagnostic::new(self, path, e.to_string()).at(e.line(), e.column())
+                })
+            }
+            Self::Toml => {
+                serde_path_to_error::deserialize(toml::Deserializer::new(data)).map_err(|e| {
+                    let path = e.path().to_string();
+                    let e = e.into_inner();
+                    let diagnostic = Diagnostic::new(self, path, e.message());
+                    match e.span() {
+                        Some(span) => diagnostic.at_offset(data, span.start),
+                        None => diagnostic,
+                    }
+                })
+            }
+            Self::Yaml => serde_path_to_error::deserialize(serde_yml::Deserializer::from_str(data))
+                .map_err(|e| {
+                    let path = e.path().to_string();
+                    let e = e.into_inner();
+                    let diagnostic = Diagnostic::new(self, path, e.to_string());
+                    match e.location() {
+                        Some(location) => diagnostic.at(location.line(), location.column()),
+                        None => diagnostic,
+                    }
+                }),
+        }
+    }
+
+    fn render(self, document: &serde_yml::Value) -> Result<String, String> {
+        match self {
+            Self::Json => serde_json::to_string_pretty(document).map_err(|e| e.to_string()),
+            Self::Toml => toml::to_string(document).map_err(|e| e.to_string()),
+            Self::Yaml => serde_yml::to_string(document).map_err(|e| e.to_string()),
+        }
+    }
+
+    fn orders(
+        self,
+        prefix: &str,
+        raw: Vec<Value>,
+        diagnostics: &mut Vec<Diagnostic>,
+    ) -> (Vec<Order>, Vec<Skipped>) {
+        let mut skipped = vec![];
+        let orders = raw
+            .into_iter()
+            .enumerate()
+            .filter_map(|(index, order)| {
+                let quantity = order.get("quantity").cloned();
+                let order = serde_path_to_error::deserialize::<_, Order>(order)
+                    .map_err(|e| {
+                        let path = join_path(&format!("{prefix}.orders[{index}]"), e.path());
+                        diagnostics.push(Diagnostic::new(self, path, e.inner().to_string()));
+                    })
+                    .ok()?;
+                if order
+                    .price
+                    .is_some_and(|price| !(price.is_finite() && price >= 0.0))
+                {
+                    let path = format!("{prefix}.orders[{index}].price");
+                    let message = "price must be a non-negative amount".to_owned();
+                    diagnostics.push(Diagnostic::new(self, path, message));
+                    return None;
+                }
+                if order.quantity.is_some() {
+                    return Some(Order { index, ..order });
+                }
+                // `DefaultOnError` swallowed the reason, so ask the quantity again
+                let reason = match quantity {
+                    Some(quantity) => u32::deserialize(quantity)
+                        .err()
+                        .map_or_else(String::new, |e| e.to_string()),
+                    None => "missing field `quantity`".to_owned(),
+                };
+                skipped.push(Skipped {
+                    index,
+                    item: order.item,
+                    reason,
+                });
+                None
+            })
+            .collect();
+        (orders, skipped)
+    }
+
+    fn inherited(
+        self,
+        workspace: cargo_manifest::Workspace<cargo_manifest::Value>,
+    ) -> Result<Inherited, Diagnostic> {
+        let metadata = workspace
+            .metadata
+            .map(serde_path_to_error::deserialize)
+            .transpose()
+            .map_err(|e| {
+                let path = join_path("workspace.metadata", e.path());
+                Diagnostic::new(self, path, e.inner().to_string())
+            })?;
+        Ok(Inherited {
+            package: workspace.package,
+            metadata,
+        })
+    }
+
+    // Inherited fields are taken from `workspace`, which is empty outside of workspaces
+    fn check(
+        self,
+        mut document: cargo_manifest::Manifest<RawMetadata>,
+        workspace: &Inherited,
+        policy: &Policy,
+        strict: bool,
+    ) -> Result<Checked, Rejected> {
+        let package = document.package.take();
+        let dependencies = dependency_tables(&document);
+        // Rules about the package fail without one, the others are still evaluated
+        let Some(mut package) = package else {
+            let (outcomes, mut diagnostics) = policy.evaluate(
+                self,
+                None,
+                &dependencies,
+                workspace,
+                "package.metadata",
+                None,
+            );
+            diagnostics.insert(
+                0,
+                Diagnostic::new(self, "package", "missing table `package`"),
+            );
+            return Err(Rejected {
+                diagnostics,
+                policy: outcomes,
+            });
+        };
+        let (prefix, metadata) = match package.metadata.take() {
+            Some(metadata) => ("package.metadata", Some(metadata)),
+            None => ("workspace.metadata", workspace.metadata.clone()),
+        };
+        let (outcomes, mut diagnostics) = policy.evaluate(
+            self,
+            Some(&package),
+            &dependencies,
+            workspace,
+            prefix,
+            metadata.as_ref(),
+        );
+
+        let (metadata, mut skipped) = match metadata {
+            Some(RawMetadata { orders, .. }) => {
+                let orders = orders.unwrap_or_default();
+                let (orders, skipped) = self.orders(prefix, orders, &mut diagnostics);
+                (Some(Metadata { orders }), skipped)
+            }
+            None => (None, vec![]),
+        };
+        if strict {
+            diagnostics.extend(skipped.drain(..).map(|Skipped { index, reason, .. }| {
+                Diagnostic::new(self, format!("{prefix}.orders[{index}].quantity"), reason)
+            }));
+        }
+        if !diagnostics.is_empty() {
+            return Err(Rejected {
+                diagnostics,
+                policy: outcomes,
+            });
+        }
+
+        let inherited_version = workspace.package.as_ref().and_then(|p| p.version.clone());
+        Ok(Checked {
+            package: PackageSummary {
+                name: package.name,
+                version: package
+                    .version
+                    .and_then(|v| v.as_local().or(inherited_version)),
+            },
+            prefix,
+            metadata,
+            skipped,
+            policy: outcomes,
+        })
+    }
+}
+
+// The lowercased type without parameters, as long as the charset is one we can read
+fn media_type(mime: &str) -> Option<String> {
+    let mut parts = mime.split(';');
+    let essence = parts.next()?.trim().to_lowercase();
+    for parameter in parts {
+        let Some((name, value)) = parameter.split_once('=') else {
+            continue;
+        };
+        let value = value.trim().trim_matches('"').to_lowercase();
+        if name.trim().eq_ignore_ascii_case("charset")
+            && !matches!(value.as_str(), "utf-8" | "utf8" | "us-ascii")
+        {
+            return None;
+        }
+    }
+    Some(essence)
+}
+
+fn invalid(diagnostics: Vec<Diagnostic>) -> Response {
+    Json(json!({ "diagnostics": diagnostics }))
+        .with_status(StatusCode::BAD_REQUEST)
+        .into_response()
+}
+
+fn rejected(
+    Rejected {
+        diagnostics,
+        policy,
+    }: Rejected,
+) -> Response {
+    Json(json!({ "diagnostics": diagnostics, "policy": policy }))
+        .with_status(StatusCode::BAD_REQUEST)
+        .into_response()
+}
+
+// What a single manifest is answered with, a plain text receipt unless asked otherwise
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Reply {
+    Text,
+    Receipt,
+    Invoice,
+    InvoiceHtml,
+}
+
+fn reply(Accept(accepted): &Accept) -> Reply {
+    accepted
+        .iter()
+        .find_map(|mime| match mime.essence_str() {
+            "application/json" => Some(Reply::Receipt),
+            INVOICE => Some(Reply::Invoice),
+            "text/html" => Some(Reply::InvoiceHtml),
+            "text/plain" | "text/*" | "*/*" => Some(Reply::Text),
+            _ => None,
+        })
+        .unwrap_or(Reply::Text)
+}
+
+// The root's own package, if it has one, gets a receipt like every member
+fn workspace(
+    root: Option<Member>,
+    members: Vec<Member>,
+    policy: &Policy,
+    strict: bool,
+    aggregate: bool,
+) -> Response {
+    let mut diagnostics = vec![];
+    let mut inherited = Inherited::default();
+    let mut packages = vec![];
+    if let Some(mut root) = root {
+        if let Some(workspace) = root.document.workspace.take() {
+            match root.format.inherited(workspace) {
+                Ok(workspace) => inherited = workspace,
+                Err(diagnostic) => diagnostics.push(diagnostic.within(&root.source)),
+            }
+        }
+        if root.document.package.is_some() {
+            packages.push(root);
+        }
+    }
+    for member in members {
+        if member.document.package.is_some() {
+            packages.push(member);
+        } else {
+            diagnostics.push(
+                Diagnostic::new(member.format, "package", "missing table `package`")
+                    .within(&member.source),
+            );
+        }
+    }
+
+    let mut receipts = vec![];
+    let mut outcomes = vec![];
+    for Member {
+        source,
+        format,
+        document,
+        data,
+        prefix,
+    } in packages
+    {
+        match format.check(document, &inherited, policy, strict) {
+            Ok(checked) => {
+                outcomes.extend(checked.policy.iter().map(|o| o.clone().within(&source)));
+                receipts.push(Receipt {
+                    source: Some(source),
+                    ..checked.receipt(aggregate)
+                });
+            }
+            Err(problems) => {
+                let problems = problems.within(&source).locate(&data, &prefix);
+                diagnostics.extend(problems.diagnostics);
+                outcomes.extend(problems.policy);
+            }
+        }
+    }
+    if !diagnostics.is_empty() {
+        return rejected(Rejected {
+            diagnostics,
+            policy: outcomes,
+        });
+    }
+
+    Json(json!({ "receipts": receipts })).into_response()
+}
+
+// Multipart uploads name the root `workspace`, every other field is a member or a standalone
+// manifest. Every part is parsed before any problem is reported.
+async fn read_members(mut multipart: Multipart) -> Result<(Option<Member>, Vec<Member>)> {
+    let mut root = None;
+    let mut members = vec![];
+    let mut diagnostics = vec![];
+    while let Some(field) = multipart.next_field().await? {
+        let name = field.name().unwrap_or_default().to_owned();
+        let source = field
+            .file_name()
+            .map_or_else(|| name.clone(), str::to_owned);
+        let Some(format) = field
+            .content_type()
+            .and_then(Format::from_mime)
+            .or_else(|| Format::from_file_name(&source))
+        else {
+            return Err(Error::from_status(StatusCode::UNSUPPORTED_MEDIA_TYPE));
+        };
+        let data = field.text().await?;
+        let document = match format.parse(&data) {
+            Ok(document) => document,
+            Err(diagnostic) => {
+                diagnostics.push(diagnostic.within(&source));
+                continue;
+            }
+        };
+        let member = Member {
+            source,
+            format,
+            document,
+            data: data.into(),
+            prefix: String::new(),
+        };
+        if name == "workspace" {
+            root = Some(member);
+        } else {
+            members.push(member);
+        }
+    }
+    if !diagnostics.is_empty() {
+        return Err(Error::from_response(invalid(diagnostics)));
+    }
+    Ok((root, members))
 }
 
 #[handler]
 async fn manifest(
-    data: String,
+    req: &Request,
+    body: Body,
     TypedHeader(content_type): TypedHeader<ContentType>,
+    Query(Options { strict, aggregate }): Query<Options>,
+    accept: Accept,
+    Data(policy): Data<&Policy>,
+    Data(catalogue): Data<&Catalogue>,
 ) -> Result<Response> {
-    let manifest = match content_type.to_string().to_lowercase().as_str() {
-        "application/json" => {
-            serde_json::from_str::<cargo_manifest::Manifest<Metadata>>(&data).ok()
-        }
-        "application/toml" => toml::from_str(&data).ok(),
-        "application/yaml" => serde_yml::from_str(&data).ok(),
-        _ => {
-            return Ok(Response::builder()
-                .status(StatusCode::UNSUPPORTED_MEDIA_TYPE)
-                .body(()))
-        }
+    let strict = strict
+        || req
+            .header("X-Strict-Orders")
+            .is_some_and(|value| value.eq_ignore_ascii_case("true"));
+    let Some(media_type) = media_type(&content_type.to_string()) else {
+        return Ok(StatusCode::UNSUPPORTED_MEDIA_TYPE.into());
     };
-    let Some(cargo_manifest::Manifest { package, .. }) = manifest else {
+
+    if media_type == "multipart/form-data" {
+        let multipart = Multipart::from_request(req, &mut RequestBody::new(body)).await?;
+        let (root, members) = read_members(multipart).await?;
+        return Ok(workspace(root, members, policy, strict, aggregate));
+    }
+    if media_type == BUNDLE {
+        let data: Arc<str> = body.into_string().await?.into();
+        let Bundle { root, members } = match Format::Json.parse(&data) {
+            Ok(bundle) => bundle,
+            Err(diagnostic) => return Ok(invalid(vec![diagnostic])),
+        };
+        let member = |source: String, document| Member {
+            prefix: source.clone(),
+            source,
+            format: Format::Json,
+            document,
+            data: data.clone(),
+        };
+        let root = root.map(|document| member("root".to_owned(), document));
+        let members = members
+            .into_iter()
+            .enumerate()
+            .map(|(index, document)| member(format!("members[{index}]"), document))
+            .collect();
+        return Ok(workspace(root, members, policy, strict, aggregate));
+    }
+
+    let Some(format) = Format::from_mime(&media_type) else {
         return Ok(Response::builder()
-            .status(StatusCode::BAD_REQUEST)
-            .body("Invalid manifest"));
+            .status(StatusCode::UNSUPPORTED_MEDIA_TYPE)
+            .body(()));
     };
-    let metadata = match package {
-        Some(package)
-            if package
-                .keywords
-                .clone()
-                .and_then(|k| k.as_local())
-                .is_some_and(|k| k.iter().any(|s| s == "Christmas 2024")) =>
-        {
-            package.metadata
-        }
-        _ => {
-            return Ok(Response::builder()
-                .status(StatusCode::BAD_REQUEST)
-                .body("Magic keyword not provided"))
+    let data = body.into_string().await?;
+    let mut document: cargo_manifest::Manifest<RawMetadata> = match format.parse(&data) {
+        Ok(document) => document,
+        Err(diagnostic) => return Ok(invalid(vec![diagnostic])),
+    };
+    // A root crate can inherit from its own `[workspace.package]`
+    let inherited = match document.workspace.take().map(|w| format.inherited(w)) {
+        Some(Ok(inherited)) => inherited,
+        Some(Err(diagnostic)) => return Ok(invalid(vec![diagnostic])),
+        None => Inherited::default(),
+    };
+    let checked = match format.check(document, &inherited, policy, strict) {
+        Ok(checked) => checked,
+        Err(problems) => return Ok(rejected(problems.locate(&data, ""))),
+    };
+
+    let reply = reply(&accept);
+    if reply == Reply::Receipt {
+        return Ok(Json(checked.receipt(aggregate)).into_response());
+    }
+    if reply == Reply::Text {
+        let outcomes = policy::header(&checked.policy);
+        let mut resp = match checked.text() {
+            Some(receipt) => receipt.into_response(),
+            None => StatusCode::NO_CONTENT.into_response(),
+        };
+        if !outcomes.is_empty() {
+            resp = resp.with_header("X-Policy", outcomes).into_response();
         }
+        return Ok(resp);
+    }
+
+    let prefix = checked.prefix;
+    let invoice = match catalogue.invoice(format, prefix, checked.receipt(aggregate)) {
+        Ok(invoice) => invoice,
+        Err(diagnostics) => return Ok(invalid(locate(diagnostics, &data, ""))),
     };
+    Ok(match reply {
+        Reply::InvoiceHtml => HtmlBody(invoice.html()).into_response(),
+        _ => Json(invoice).with_content_type(INVOICE).into_response(),
+    })
+}
 
-    let Some(Metadata { orders }) = metadata else {
-        return Ok(StatusCode::NO_CONTENT.into());
+#[handler]
+async fn list_dependencies(
+    data: String,
+    TypedHeader(content_type): TypedHeader<ContentType>,
+) -> Result<Response> {
+    let Some(format) = Format::from_mime(&content_type.to_string()) else {
+        return Ok(StatusCode::UNSUPPORTED_MEDIA_TYPE.into());
+    };
+    let document = match format.parse::<cargo_manifest::Manifest<RawMetadata>>(&data) {
+        Ok(document) => document,
+        Err(diagnostic) => return Ok(invalid(vec![diagnostic])),
     };
 
-    let order_receipt = orders
-        .iter()
-        .filter_map(|Order { item, quantity }| {
-            quantity.map(|quantity| format!("{item}: {quantity}"))
+    Ok(Json(json!({ "dependencies": dependencies::list(&document) })).into_response())
+}
+
+// YAML mappings keep their keys in order, so documents go through `serde_yml::Value`
+#[handler]
+async fn convert(
+    data: String,
+    TypedHeader(content_type): TypedHeader<ContentType>,
+    Accept(accepted): Accept,
+) -> Result<Response> {
+    let Some(from) = Format::from_mime(&content_type.to_string()) else {
+        return Ok(StatusCode::UNSUPPORTED_MEDIA_TYPE.into());
+    };
+    let to = if accepted.is_empty() {
+        Some(from)
+    } else {
+        accepted.iter().find_map(|mime| match mime.essence_str() {
+            "*/*" => Some(from),
+            essence => Format::from_mime(essence),
         })
-        .collect::<Vec<String>>();
+    };
+    let Some(to) = to else {
+        return Ok(StatusCode::NOT_ACCEPTABLE.into());
+    };
 
-    if order_receipt.is_empty() {
-        return Ok(StatusCode::NO_CONTENT.into());
+    if let Err(diagnostic) = from.parse::<cargo_manifest::Manifest<RawMetadata>>(&data) {
+        return Ok(invalid(vec![diagnostic]));
     }
+    // Passing the manifest through untouched is the only way to keep its comments
+    if from == to {
+        return Ok(data.with_content_type(to.mime()).into_response());
+    }
+    let document = match from.parse(&data) {
+        Ok(document) => document,
+        Err(diagnostic) => return Ok(invalid(vec![diagnostic])),
+    };
+
+    Ok(match to.render(&document) {
+        Ok(converted) => converted.with_content_type(to.mime()).into_response(),
+        Err(message) => invalid(vec![Diagnostic::new(to, ".", message)]),
+    })
+}
 
-    Ok(order_receipt.join("\n").into())
+pub fn day_five(policy: Policy, catalogue: Catalogue) -> impl Endpoint {
+    Route::new()
+        .at("/manifest", post(manifest))
+        .at("/convert", post(convert))
+        .at("/dependencies", post(list_dependencies))
+        .with(Compression::new())
+        // Bodies may be posted compressed, replies always go out as they are
+        .before(|mut req| async move {
+            req.headers_mut().remove(ACCEPT_ENCODING);
+            Ok(req)
+        })
+        .data(policy)
+        .data(catalogue)
 }
 
-pub fn day_five() -> Route {
-    Route::new().at("/manifest", post(manifest))
+#[cfg(test)]
+mod tests {
+    use std::{env, fs, path::PathBuf};
+
+    use poem::{
+        test::{TestClient, TestForm, TestFormField, TestJson},
+        web::{Compress, CompressionAlgo},
+    };
+
+    use super::*;
+
+    fn root() -> PathBuf {
+        PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+    }
+
+    fn default_policy() -> Policy {
+        Policy::load(root().join("assets/manifest_policy.toml")).unwrap()
+    }
+
+    fn default_catalogue() -> Catalogue {
+        Catalogue::load(root().join("assets/price_catalogue.toml")).unwrap()
+    }
+
+    fn client_with(policy: Policy, catalogue: Catalogue) -> TestClient<impl Endpoint> {
+        TestClient::new(day_five(policy, catalogue))
+    }
+
+    fn client() -> TestClient<impl Endpoint> {
+        client_with(default_policy(), default_catalogue())
+    }
+
+    // Every `<case>.<format>` fixture is posted to `/manifest` and has to get the answer in
+    // `<case>.<format>.golden`: the status code on the first line, the body after it.
+    // Set `UPDATE_GOLDEN` to rewrite the golden files from the current responses.
+    #[tokio::test]
+    async fn fixtures() {
+        let cli = client();
+        let update = env::var_os("UPDATE_GOLDEN").is_some();
+
+        let mut fixtures = fs::read_dir(root().join("tests/fixtures/manifest"))
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect::<Vec<_>>();
+        fixtures.sort();
+
+        let mut checked = 0;
+        let mut mismatches = vec![];
+        for fixture in fixtures {
+            let name = fixture.file_name().unwrap().to_string_lossy().into_owned();
+            let Some(format) = Format::from_file_name(&name) else {
+                continue;
+            };
+
+            let resp = cli
+                .post("/manifest")
+                .content_type(format.mime())
+                .body(fs::read_to_string(&fixture).unwrap())
+                .send()
+                .await;
+            let status = resp.0.status().as_u16();
+            let body = resp.0.into_body().into_string().await.unwrap();
+            let actual = format!("{status}\n{body}");
+
+            let golden = fixture.with_file_name(format!("{name}.golden"));
+            if update {
+                fs::write(&golden, format!("{actual}\n")).unwrap();
+            } else if fs::read_to_string(&golden).unwrap_or_default().trim_end()
+                != actual.trim_end()
+            {
+                mismatches.push(format!("{name}:\n{actual}"));
+            }
+            checked += 1;
+        }
+
+        assert!(checked > 0, "no fixtures found");
+        assert!(mismatches.is_empty(), "{}", mismatches.join("\n\n"));
+    }
+
+    #[test]
+    fn media_types() {
+        assert_eq!(Format::from_mime("application/toml"), Some(Format::Toml));
+        assert_eq!(
+            Format::from_mime("Application/X-TOML; charset=UTF-8"),
+            Some(Format::Toml)
+        );
+        assert_eq!(Format::from_mime("text/yaml"), Some(Format::Yaml));
+        assert_eq!(Format::from_mime("application/json; charset=latin1"), None);
+        assert_eq!(Format::from_mime("text/plain"), None);
+    }
+
+    #[test]
+    fn aggregate() {
+        let order = |index, item: &str, quantity| Order {
+            index,
+            item: item.to_owned(),
+            quantity,
+            price: None,
+            currency: None,
+        };
+        let metadata = Metadata {
+            orders: vec![
+                order(0, "Toy car", Some(2)),
+                order(1, "Ball", Some(1)),
+                order(2, "Toy car", Some(u32::MAX)),
+            ],
+        }
+        .aggregate();
+
+        let merged = metadata
+            .orders
+            .iter()
+            .map(|order| (order.index, order.item.as_str(), order.quantity))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            merged,
+            [(0, "Toy car", Some(u32::MAX)), (1, "Ball", Some(1))]
+        );
+    }
+
+    #[tokio::test]
+    async fn invoice() {
+        let catalogue =
+            toml::from_str("currency = \"EUR\"\ntax-rate = 0.07\n[prices]\n\"Toy car\" = 4.99")
+                .unwrap();
+        let cli = client_with(default_policy(), catalogue);
+        let document = r#"
+            [package]
+            name = "<shop>"
+            keywords = ["Christmas 2024"]
+
+            [[package.metadata.orders]]
+            item = "Toy car"
+            quantity = 3
+
+            [[package.metadata.orders]]
+            item = "<b>Ball</b>"
+            quantity = 2
+            price = 1.5
+            currency = "eur"
+        "#;
+
+        let resp = cli
+            .post("/manifest")
+            .content_type("application/toml")
+            .header("Accept", INVOICE)
+            .body(document)
+            .send()
+            .await;
+        resp.assert_status_is_ok();
+        resp.assert_content_type(INVOICE);
+        let invoice = resp.json().await;
+        let invoice = invoice.value();
+        invoice.object().get("subtotal").assert_string("17.97");
+        invoice.object().get("tax").assert_string("1.26");
+        invoice.object().get("total").assert_string("19.23");
+        let lines = invoice.object().get("lines").array();
+        lines.get(0).object().get("total").assert_string("14.97");
+        lines
+            .get(1)
+            .object()
+            .get("unit_price")
+            .assert_string("1.50");
+
+        let resp = cli
+            .post("/manifest")
+            .content_type("application/toml")
+            .header("Accept", "text/html")
+            .body(document)
+            .send()
+            .await;
+        resp.assert_status_is_ok();
+        let html = resp.0.into_body().into_string().await.unwrap();
+        assert!(html.contains("&lt;b&gt;Ball&lt;/b&gt;"), "{html}");
+        assert!(html.contains("&lt;shop&gt;"), "{html}");
+        assert!(!html.contains("<b>"), "{html}");
+        assert!(html.contains("Tax (7%)"), "{html}");
+
+        let resp = cli
+            .post("/manifest")
+            .content_type("application/toml")
+            .header("Accept", INVOICE)
+            .body(document.replace("price = 1.5\n", ""))
+            .send()
+            .await;
+        resp.assert_status(StatusCode::BAD_REQUEST);
+        let json = resp.json().await;
+        let diagnostic = json
+            .value()
+            .object()
+            .get("diagnostics")
+            .array()
+            .get(0)
+            .object();
+        diagnostic
+            .get("path")
+            .assert_string("package.metadata.orders[1]");
+        diagnostic.get("line").assert_i64(10);
+
+        // The unpriced order is second once the cars are merged, but reported where it was
+        let document = r#"
+            [workspace.metadata]
+            orders = [
+                { item = "Toy car", quantity = 1 },
+                { item = "Toy car", quantity = 1 },
+                { item = "Ball", quantity = 1, currency = "USD" },
+            ]
+
+            [package]
+            name = "shop"
+            keywords = ["Christmas 2024"]
+        "#;
+        let resp = cli
+            .post("/manifest")
+            .content_type("application/toml")
+            .header("Accept", INVOICE)
+            .body(document)
+            .send()
+            .await;
+        resp.assert_status(StatusCode::BAD_REQUEST);
+        let json = resp.json().await;
+        let diagnostic = json
+            .value()
+            .object()
+            .get("diagnostics")
+            .array()
+            .get(0)
+            .object();
+        diagnostic
+            .get("path")
+            .assert_string("workspace.metadata.orders[2].currency");
+        diagnostic.get("line").assert_i64(6);
+    }
+
+    #[tokio::test]
+    async fn policy() {
+        let document = "[package]\nname = \"shop\"\nkeywords = [\"Christmas 2024\"]\n";
+        let resp = client()
+            .post("/manifest")
+            .content_type("application/toml")
+            .body(document)
+            .send()
+            .await;
+        resp.assert_status(StatusCode::NO_CONTENT);
+        resp.assert_header("X-Policy", "keywords=passed");
+
+        let policy = toml::from_str("banned-dependencies = [\"openssl\"]").unwrap();
+        let resp = client_with(policy, default_catalogue())
+            .post("/manifest")
+            .content_type("application/toml")
+            .body("[dependencies]\nopenssl = \"0.10\"\n")
+            .send()
+            .await;
+        resp.assert_status(StatusCode::BAD_REQUEST);
+        let json = resp.json().await;
+        let diagnostics = json.value().object().get("diagnostics").array();
+        diagnostics.assert_len(2);
+        let missing = diagnostics.get(0).object();
+        missing
+            .get("message")
+            .assert_string("missing table `package`");
+        let banned = diagnostics.get(1).object();
+        banned.get("rule").assert_string("banned-dependencies");
+        let outcome = json.value().object().get("policy").array().get(0).object();
+        outcome.get("rule").assert_string("banned-dependencies");
+        outcome.get("passed").assert_bool(false);
+    }
+
+    #[tokio::test]
+    async fn convert() {
+        let cli = client();
+        let document = "# shop\n[package]\nname = \"shop\"\nversion = \"0.1.0\"\n";
+
+        let resp = cli
+            .post("/convert")
+            .content_type("application/toml")
+            .header("Accept", "application/json")
+            .body(document)
+            .send()
+            .await;
+        resp.assert_status_is_ok();
+        resp.assert_content_type("application/json");
+        let json = resp.json().await;
+        let package = json.value().object().get("package").object();
+        package.get("name").assert_string("shop");
+        package.get("version").assert_string("0.1.0");
+
+        let resp = cli
+            .post("/convert")
+            .content_type("application/json")
+            .header("Accept", "application/yaml")
+            .body(r#"{"package":{"name":"shop"}}"#)
+            .send()
+            .await;
+        resp.assert_status_is_ok();
+        resp.assert_content_type("application/yaml");
+        resp.assert_text("package:\n  name: shop\n").await;
+
+        let resp = cli
+            .post("/convert")
+            .content_type("application/toml")
+            .header("Accept", "application/toml")
+            .body(document)
+            .send()
+            .await;
+        resp.assert_status_is_ok();
+        resp.assert_text(document).await;
+
+        let resp = cli
+            .post("/convert")
+            .content_type("application/toml")
+            .header("Accept", "text/plain")
+            .body(document)
+            .send()
+            .await;
+        resp.assert_status(StatusCode::NOT_ACCEPTABLE);
+
+        let resp = cli
+            .post("/convert")
+            .content_type("application/toml")
+            .header("Accept", "application/json")
+            .body("[package]\nname = 3\n")
+            .send()
+            .await;
+        resp.assert_status(StatusCode::BAD_REQUEST);
+    }
+
+    // Keys keep their order both ways, only the comments are lost
+    #[tokio::test]
+    async fn round_trip() {
+        let cli = client();
+        let document = r#"# shop
+[package]
+name = "shop"
+version = "0.1.0"
+edition = "2021"
+authors = ["Santa"]
+
+# what to pack
+[package.metadata]
+wrapping = "red"
+bows = 2
+
+[[package.metadata.orders]]
+item = "Toy car"
+quantity = 2
+
+[[package.metadata.orders]]
+item = "Lego brick"
+quantity = 230
+
+[dependencies]
+serde = "1"
+"#;
+
+        let resp = cli
+            .post("/convert")
+            .content_type("application/toml")
+            .header("Accept", "application/json")
+            .body(document)
+            .send()
+            .await;
+        resp.assert_status_is_ok();
+        let json = resp.0.into_body().into_string().await.unwrap();
+
+        let resp = cli
+            .post("/convert")
+            .content_type("application/json")
+            .header("Accept", "application/toml")
+            .body(json)
+            .send()
+            .await;
+        resp.assert_status_is_ok();
+        resp.assert_content_type("application/toml");
+        let uncommented = document
+            .lines()
+            .filter(|line| !line.starts_with('#'))
+            .map(|line| format!("{line}\n"))
+            .collect::<String>();
+        resp.assert_text(uncommented).await;
+    }
+
+    #[tokio::test]
+    async fn dependencies() {
+        let resp = client()
+            .post("/dependencies")
+            .content_type("application/toml")
+            .body(
+                r#"
+                [package]
+                name = "shop"
+
+                [dependencies]
+                serde = "1"
+                serde1 = { package = "serde", version = "1.0" }
+                rand = "*"
+                tracing = { git = "https://github.com/tokio-rs/tracing" }
+                log = { git = "https://github.com/rust-lang/log", rev = "abc123" }
+
+                [target.'cfg(unix)'.dev-dependencies]
+                libc = "0.2"
+                "#,
+            )
+            .send()
+            .await;
+        resp.assert_status_is_ok();
+        let json = resp.json().await;
+        let entries = json.value().object().get("dependencies").array();
+        entries.assert_len(6);
+
+        let entry = |name: &str| {
+            entries
+                .iter()
+                .find(|entry| entry.object().get("name").string() == name)
+                .unwrap()
+                .object()
+        };
+        let flags = |name: &str| {
+            entry(name)
+                .get("flags")
+                .array()
+                .iter()
+                .map(|flag| flag.string().to_owned())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(flags("serde"), ["renamed-duplicate"]);
+        assert_eq!(flags("serde1"), ["renamed-duplicate"]);
+        assert_eq!(flags("rand"), ["wildcard-version"]);
+        assert_eq!(flags("tracing"), ["unpinned-git"]);
+        assert!(flags("log").is_empty());
+        assert!(flags("libc").is_empty());
+
+        entry("serde1").get("package").assert_string("serde");
+        entry("libc").get("kind").assert_string("dev");
+        entry("libc").get("target").assert_string("cfg(unix)");
+    }
+
+    #[tokio::test]
+    async fn workspaces() {
+        let cli = client();
+        let root = r#"
+            [workspace]
+            members = ["shop"]
+
+            [workspace.package]
+            version = "1.2.3"
+            keywords = ["Christmas 2024"]
+
+            [[workspace.metadata.orders]]
+            item = "Toy car"
+            quantity = 2
+        "#;
+        let member =
+            "[package]\nname = \"shop\"\nversion.workspace = true\nkeywords.workspace = true\n";
+        let assert_receipt = |json: TestJson, source: &str| {
+            let receipts = json.value().object().get("receipts").array();
+            receipts.assert_len(1);
+            let receipt = receipts.get(0).object();
+            receipt.get("source").assert_string(source);
+            receipt
+                .get("package")
+                .object()
+                .get("version")
+                .assert_string("1.2.3");
+            receipt.get("total_quantity").assert_i64(2);
+            let order = receipt.get("orders").array().get(0).object();
+            order.get("item").assert_string("Toy car");
+        };
+
+        let form = |member: &str| {
+            TestForm::new()
+                .field(
+                    TestFormField::text(root)
+                        .name("workspace")
+                        .filename("Cargo.toml")
+                        .content_type("application/toml"),
+                )
+                .field(
+                    TestFormField::text(member)
+                        .name("shop")
+                        .filename("shop/Cargo.toml"),
+                )
+        };
+        let resp = cli.post("/manifest").multipart(form(member)).send().await;
+        resp.assert_status_is_ok();
+        assert_receipt(resp.json().await, "shop/Cargo.toml");
+
+        let resp = cli
+            .post("/manifest")
+            .multipart(form("[package]\nname = \"shop\"\n"))
+            .send()
+            .await;
+        resp.assert_status(StatusCode::BAD_REQUEST);
+
+        let to_json = |toml: &str| toml::from_str::<Value>(toml).unwrap();
+        let resp = cli
+            .post("/manifest")
+            .content_type(BUNDLE)
+            .body(json!({ "root": to_json(root), "members": [to_json(member)] }).to_string())
+            .send()
+            .await;
+        resp.assert_status_is_ok();
+        assert_receipt(resp.json().await, "members[0]");
+
+        let resp = cli
+            .post("/manifest")
+            .content_type("application/toml")
+            .header("Accept", "application/json")
+            .body(format!("{member}{root}"))
+            .send()
+            .await;
+        resp.assert_status_is_ok();
+        let json = resp.json().await;
+        let package = json.value().object().get("package").object();
+        package.get("version").assert_string("1.2.3");
+    }
+
+    // Parts without a `workspace` root are checked as packages of their own
+    #[tokio::test]
+    async fn standalone_parts() {
+        let part = |name: &str, item: &str| {
+            let document = format!(
+                "[package]\nname = \"{name}\"\nkeywords = [\"Christmas 2024\"]\n\n\
+                 [[package.metadata.orders]]\nitem = \"{item}\"\nquantity = 1\n"
+            );
+            TestFormField::text(document)
+                .name(name.to_owned())
+                .filename(format!("{name}/Cargo.toml"))
+        };
+        let form = TestForm::new()
+            .field(part("shop", "Toy car"))
+            .field(part("mall", "Lego brick"));
+        let resp = client().post("/manifest").multipart(form).send().await;
+        resp.assert_status_is_ok();
+        let json = resp.json().await;
+        let receipts = json.value().object().get("receipts").array();
+        receipts.assert_len(2);
+        for (index, (source, item)) in [
+            ("shop/Cargo.toml", "Toy car"),
+            ("mall/Cargo.toml", "Lego brick"),
+        ]
+        .into_iter()
+        .enumerate()
+        {
+            let receipt = receipts.get(index).object();
+            receipt.get("source").assert_string(source);
+            let order = receipt.get("orders").array().get(0).object();
+            order.get("item").assert_string(item);
+        }
+    }
+
+    #[tokio::test]
+    async fn compressed() {
+        let cli = client();
+        let document = "[package]\nname = \"shop\"\nkeywords = [\"Christmas 2024\"]\n\n\
+                        [[package.metadata.orders]]\nitem = \"Toy car\"\nquantity = 2\n";
+        for (algo, coding) in [
+            (CompressionAlgo::GZIP, "gzip"),
+            (CompressionAlgo::ZSTD, "zstd"),
+        ] {
+            let body = Compress::new(document, algo)
+                .into_response()
+                .into_body()
+                .into_bytes()
+                .await
+                .unwrap();
+            let resp = cli
+                .post("/manifest")
+                .content_type("application/toml")
+                .header("Content-Encoding", coding)
+                .header("Accept-Encoding", coding)
+                .body(body)
+                .send()
+                .await;
+            resp.assert_status_is_ok();
+            resp.assert_header_is_not_exist("Content-Encoding");
+            resp.assert_text("Toy car: 2").await;
+        }
+    }
 }
@@ -0,0 +1,190 @@
+use std::collections::BTreeMap;
+
+use serde::Serialize;
+
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum DependencyKind {
+    Normal,
+    Dev,
+    Build,
+    Workspace,
+}
+
+#[derive(Debug)]
+pub struct DependencyTable<'a> {
+    kind: DependencyKind,
+    target: Option<&'a str>,
+    pub dependencies: &'a cargo_manifest::DepsSet,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum Source {
+    Registry,
+    Git,
+    Path,
+    Workspace,
+}
+
+// One dependency, the same shape whichever way the manifest spelled it
+#[derive(Debug, Serialize)]
+pub struct DependencyEntry {
+    name: String,
+    package: String,
+    kind: DependencyKind,
+    target: Option<String>,
+    version: Option<String>,
+    source: Source,
+    location: Option<String>,
+    features: Vec<String>,
+    optional: bool,
+    flags: Vec<&'static str>,
+}
+
+// Every dependency table of a manifest
+pub fn dependency_tables<M>(document: &cargo_manifest::Manifest<M>) -> Vec<DependencyTable<'_>> {
+    let table = |kind, target, dependencies| DependencyTable {
+        kind,
+        target,
+        dependencies,
+    };
+    let mut tables = vec![];
+    for (kind, dependencies) in [
+        (DependencyKind::Normal, document.dependencies.as_ref()),
+        (DependencyKind::Dev, document.dev_dependencies.as_ref()),
+        (DependencyKind::Build, document.build_dependencies.as_ref()),
+        (
+            DependencyKind::Workspace,
+            document
+                .workspace
+                .as_ref()
+                .and_then(|w| w.dependencies.as_ref()),
+        ),
+    ] {
+        if let Some(dependencies) = dependencies {
+            tables.push(table(kind, None, dependencies));
+        }
+    }
+    for (cfg, target) in document.target.iter().flatten() {
+        let cfg = Some(cfg.as_str());
+        tables.push(table(DependencyKind::Normal, cfg, &target.dependencies));
+        tables.push(table(DependencyKind::Dev, cfg, &target.dev_dependencies));
+        tables.push(table(
+            DependencyKind::Build,
+            cfg,
+            &target.build_dependencies,
+        ));
+    }
+    tables
+}
+
+// Dependencies of one crate listed under several names are flagged on every entry
+pub fn list<M>(document: &cargo_manifest::Manifest<M>) -> Vec<DependencyEntry> {
+    let mut entries = dependency_tables(document)
+        .iter()
+        .flat_map(DependencyTable::entries)
+        .collect::<Vec<_>>();
+    let mut names = BTreeMap::<String, Vec<String>>::new();
+    for entry in &entries {
+        let names = names.entry(entry.package.clone()).or_default();
+        if !names.contains(&entry.name) {
+            names.push(entry.name.clone());
+        }
+    }
+    for entry in &mut entries {
+        if names[&entry.package].len() > 1 {
+            entry.flags.push("renamed-duplicate");
+        }
+    }
+    entries
+}
+
+impl DependencyTable<'_> {
+    pub fn path(&self) -> String {
+        let table = match self.kind {
+            DependencyKind::Normal => "dependencies",
+            DependencyKind::Dev => "dev-dependencies",
+            DependencyKind::Build => "build-dependencies",
+            DependencyKind::Workspace => return "workspace.dependencies".to_owned(),
+        };
+        match self.target {
+            Some(cfg) => format!("target.'{cfg}'.{table}"),
+            None => table.to_owned(),
+        }
+    }
+
+    fn entries(&self) -> impl Iterator<Item = DependencyEntry> + '_ {
+        self.dependencies.iter().map(|(key, dependency)| {
+            let mut entry = DependencyEntry {
+                name: key.clone(),
+                package: package_name(key, dependency).to_owned(),
+                kind: self.kind,
+                target: self.target.map(str::to_owned),
+                version: None,
+                source: Source::Registry,
+                location: None,
+                features: vec![],
+                optional: false,
+                flags: vec![],
+            };
+            match dependency {
+                cargo_manifest::Dependency::Simple(version) => {
+                    entry.version = Some(version.clone());
+                }
+                cargo_manifest::Dependency::Inherited(detail) => {
+                    entry.source = Source::Workspace;
+                    entry.features = detail.features.clone().unwrap_or_default();
+                    entry.optional = detail.optional.unwrap_or_default();
+                }
+                cargo_manifest::Dependency::Detailed(detail) => {
+                    entry.version = detail.version.clone();
+                    (entry.source, entry.location) = match (&detail.git, &detail.path) {
+                        (Some(git), _) => (Source::Git, Some(git.clone())),
+                        (None, Some(path)) => (Source::Path, Some(path.clone())),
+                        (None, None) => (Source::Registry, detail.registry.clone()),
+                    };
+                    entry.features = detail.features.clone().unwrap_or_default();
+                    entry.optional = detail.optional.unwrap_or_default();
+                    if entry.source == Source::Git && detail.rev.is_none() {
+                        entry.flags.push("unpinned-git");
+                    }
+                }
+            }
+            // A registry dependency without a version takes any version
+            if entry.source == Source::Registry && entry.version.as_deref().is_none_or(is_wildcard)
+            {
+                entry.flags.push("wildcard-version");
+            }
+            entry
+        })
+    }
+}
+
+pub fn package_name<'a>(key: &'a str, dependency: &'a cargo_manifest::Dependency) -> &'a str {
+    match dependency {
+        cargo_manifest::Dependency::Detailed(detail) => detail.package.as_deref().unwrap_or(key),
+        _ => key,
+    }
+}
+
+// `*`, `1.*` and `1.x` all leave part of the version open
+fn is_wildcard(requirement: &str) -> bool {
+    requirement
+        .split([',', '.', ' '])
+        .any(|part| matches!(part.trim_start_matches(['=', '^', '~']), "*" | "x" | "X"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wildcards() {
+        assert!(is_wildcard("*"));
+        assert!(is_wildcard("1.*"));
+        assert!(is_wildcard(">=1.2, 2.x"));
+        assert!(!is_wildcard("1.0"));
+        assert!(!is_wildcard("^0.10"));
+    }
+}
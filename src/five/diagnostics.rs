@@ -0,0 +1,275 @@
+use std::fmt;
+
+use serde::{
+    de::{self, DeserializeSeed, IgnoredAny, MapAccess, SeqAccess, Visitor},
+    Deserializer, Serialize,
+};
+
+use super::Format;
+
+// A problem with a manifest, located by its path and, where the format allows, line and column
+#[derive(Debug, Serialize)]
+pub struct Diagnostic {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    source: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    rule: Option<&'static str>,
+    format: Format,
+    line: Option<u32>,
+    column: Option<u32>,
+    path: String,
+    message: String,
+}
+
+impl Diagnostic {
+    pub fn new(format: Format, path: impl Into<String>, message: impl Into<String>) -> Self {
+        Self {
+            source: None,
+            rule: None,
+            format,
+            line: None,
+            column: None,
+            path: path.into(),
+            message: message.into(),
+        }
+    }
+
+    // Names the uploaded manifest the problem is in, for workspaces
+    pub fn within(mut self, source: &str) -> Self {
+        self.source = Some(source.to_owned());
+        self
+    }
+
+    pub fn for_rule(mut self, rule: &'static str) -> Self {
+        self.rule = Some(rule);
+        self
+    }
+
+    // Positions are 1-based, 0 means the deserializer did not know where it was
+    pub fn at(mut self, line: usize, column: usize) -> Self {
+        if line == 0 {
+            return self;
+        }
+        let suffix = format!(" at line {line} column {column}");
+        if let Some(message) = self.message.strip_suffix(&suffix) {
+            self.message = message.to_owned();
+        }
+        self.line = u32::try_from(line).ok();
+        self.column = u32::try_from(column).ok();
+        self
+    }
+
+    pub fn at_offset(self, data: &str, offset: usize) -> Self {
+        let before = &data[..offset.min(data.len())];
+        let line = before.matches('\n').count() + 1;
+        let column = before
+            .rsplit('\n')
+            .next()
+            .unwrap_or_default()
+            .chars()
+            .count()
+            + 1;
+        self.at(line, column)
+    }
+
+    // Problems found after parsing only know their path, `data` is read again to find it.
+    // `prefix` is where the manifest sits in `data`, for bundles.
+    pub fn locate(self, data: &str, prefix: &str) -> Self {
+        if self.line.is_some() {
+            return self;
+        }
+        let path = match prefix {
+            "" => self.path.clone(),
+            prefix => format!("{prefix}.{}", self.path),
+        };
+        let locate = Locate(&segments(&path));
+        match self.format {
+            Format::Json => {
+                match locate.deserialize(&mut serde_json::Deserializer::from_str(data)) {
+                    Err(e) if e.to_string().contains(LOCATED) => self.at(e.line(), e.column()),
+                    _ => self,
+                }
+            }
+            Format::Toml => match locate.deserialize(toml::Deserializer::new(data)) {
+                Err(e) if e.message() == LOCATED => match e.span() {
+                    Some(span) => self.at_offset(data, span.start),
+                    None => self,
+                },
+                _ => self,
+            },
+            Format::Yaml => match locate.deserialize(serde_yml::Deserializer::from_str(data)) {
+                Err(e) if e.to_string().contains(LOCATED) => match e.location() {
+                    Some(location) => self.at(location.line(), location.column()),
+                    None => self,
+                },
+                _ => self,
+            },
+        }
+    }
+}
+
+pub fn locate(diagnostics: Vec<Diagnostic>, data: &str, prefix: &str) -> Vec<Diagnostic> {
+    diagnostics
+        .into_iter()
+        .map(|diagnostic| diagnostic.locate(data, prefix))
+        .collect()
+}
+
+pub fn join_path(prefix: &str, path: &serde_path_to_error::Path) -> String {
+    if path.iter().next().is_none() {
+        return prefix.to_owned();
+    }
+    format!("{prefix}.{path}")
+}
+
+// What `Locate` fails with once it reaches its path, every other error means it wasn't there
+const LOCATED: &str = "located diagnostic path";
+
+enum Segment<'a> {
+    Key(&'a str),
+    Index(usize),
+}
+
+// `package.metadata.orders[1].item`, keys may be quoted like `target.'cfg(unix)'`
+fn segments(path: &str) -> Vec<Segment<'_>> {
+    let mut segments = vec![];
+    for part in path.split('.').filter(|part| !part.is_empty()) {
+        let mut pieces = part.split('[');
+        let key = pieces.next().unwrap_or_default().trim_matches('\'');
+        if !key.is_empty() {
+            segments.push(Segment::Key(key));
+        }
+        for index in pieces.filter_map(|index| index.trim_end_matches(']').parse().ok()) {
+            segments.push(Segment::Index(index));
+        }
+    }
+    segments
+}
+
+// Walks a document down to a path and fails there, so that the format's own error carries the
+// position. Everything else is read and ignored, which keeps the deserializer happy.
+struct Locate<'a>(&'a [Segment<'a>]);
+
+impl Locate<'_> {
+    fn scalar<E: de::Error>(self) -> Result<(), E> {
+        match self.0 {
+            [] => Err(E::custom(LOCATED)),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl<'de> DeserializeSeed<'de> for Locate<'_> {
+    type Value = ();
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<(), D::Error> {
+        deserializer.deserialize_any(self)
+    }
+}
+
+impl<'de> Visitor<'de> for Locate<'_> {
+    type Value = ();
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("any value")
+    }
+
+    fn visit_bool<E: de::Error>(self, _: bool) -> Result<(), E> {
+        self.scalar()
+    }
+
+    fn visit_i64<E: de::Error>(self, _: i64) -> Result<(), E> {
+        self.scalar()
+    }
+
+    fn visit_u64<E: de::Error>(self, _: u64) -> Result<(), E> {
+        self.scalar()
+    }
+
+    fn visit_f64<E: de::Error>(self, _: f64) -> Result<(), E> {
+        self.scalar()
+    }
+
+    fn visit_str<E: de::Error>(self, _: &str) -> Result<(), E> {
+        self.scalar()
+    }
+
+    fn visit_unit<E: de::Error>(self) -> Result<(), E> {
+        self.scalar()
+    }
+
+    fn visit_none<E: de::Error>(self) -> Result<(), E> {
+        self.scalar()
+    }
+
+    fn visit_some<D: Deserializer<'de>>(self, deserializer: D) -> Result<(), D::Error> {
+        self.deserialize(deserializer)
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<(), A::Error> {
+        let (target, rest) = match self.0 {
+            [] => return Err(de::Error::custom(LOCATED)),
+            [Segment::Index(index), rest @ ..] => (Some(*index), rest),
+            [Segment::Key(_), ..] => (None, &[][..]),
+        };
+        for index in 0.. {
+            let next = if target == Some(index) {
+                seq.next_element_seed(Locate(rest))?
+            } else {
+                seq.next_element::<IgnoredAny>()?.map(drop)
+            };
+            if next.is_none() {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<(), A::Error> {
+        let (target, rest) = match self.0 {
+            [] => return Err(de::Error::custom(LOCATED)),
+            [Segment::Key(key), rest @ ..] => (Some(*key), rest),
+            [Segment::Index(_), ..] => (None, &[][..]),
+        };
+        while let Some(key) = map.next_key::<String>()? {
+            if target == Some(key.as_str()) {
+                map.next_value_seed(Locate(rest))?;
+            } else {
+                map.next_value::<IgnoredAny>()?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{super::RawMetadata, *};
+
+    #[test]
+    fn positions() {
+        let data = "[package]\nname = 3\n";
+        let diagnostic = Diagnostic::new(Format::Toml, "package.name", "bad").at_offset(data, 17);
+        assert_eq!((diagnostic.line, diagnostic.column), (Some(2), Some(8)));
+
+        let diagnostic = Format::Json
+            .parse::<cargo_manifest::Manifest<RawMetadata>>("{\n  \"package\": {\"name\": 3}\n}")
+            .unwrap_err();
+        assert_eq!(diagnostic.path, "package.name");
+        assert_eq!(diagnostic.line, Some(2));
+        assert!(!diagnostic.message.contains("at line"));
+
+        let data = "[package]\nname = \"a\"\n\n[[package.metadata.orders]]\nitem = \"Ball\"\n\n[[package.metadata.orders]]\nitem = 3\n";
+        let diagnostic = Diagnostic::new(Format::Toml, "package.metadata.orders[1].item", "bad")
+            .locate(data, "");
+        assert_eq!((diagnostic.line, diagnostic.column), (Some(8), Some(8)));
+
+        let data = "{\"members\": [{}, {\n\"package\": {\"keywords\": []}}]}";
+        let diagnostic =
+            Diagnostic::new(Format::Json, "package.keywords", "bad").locate(data, "members[1]");
+        assert_eq!(diagnostic.line, Some(2));
+        let diagnostic =
+            Diagnostic::new(Format::Json, "package.license", "bad").locate(data, "members[1]");
+        assert_eq!(diagnostic.line, None);
+    }
+}
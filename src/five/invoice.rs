@@ -0,0 +1,183 @@
+use std::{collections::BTreeMap, error::Error as StdError, fmt, fs, path::Path};
+
+use askama_escape::{escape, Html};
+use serde::{Deserialize, Serialize};
+
+use super::{diagnostics::Diagnostic, Format, PackageSummary, Receipt, Skipped};
+
+/// Prices for items whose orders don't carry their own, read from a TOML file.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct Catalogue {
+    // The invoice currency, every priced order has to be in it
+    currency: String,
+    #[serde(default)]
+    tax_rate: f64,
+    #[serde(default)]
+    prices: BTreeMap<String, f64>,
+}
+
+// An amount in cents, so that line totals add up exactly
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Money(u64);
+
+#[derive(Debug, Serialize)]
+struct InvoiceLine {
+    item: String,
+    quantity: u32,
+    unit_price: Money,
+    total: Money,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Invoice {
+    package: PackageSummary,
+    currency: String,
+    lines: Vec<InvoiceLine>,
+    subtotal: Money,
+    tax_rate: f64,
+    tax: Money,
+    total: Money,
+    skipped: Vec<Skipped>,
+}
+
+impl fmt::Display for Money {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{:02}", self.0 / 100, self.0 % 100)
+    }
+}
+
+// Amounts go out as decimal strings, floats would lose cents on large totals
+impl Serialize for Money {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl Money {
+    fn from_amount(amount: f64) -> Self {
+        Self((amount * 100.0).round() as u64)
+    }
+}
+
+impl Catalogue {
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, Box<dyn StdError>> {
+        let catalogue: Self = toml::from_str(&fs::read_to_string(path)?)?;
+        let valid = |amount: f64| amount.is_finite() && amount >= 0.0;
+        if !valid(catalogue.tax_rate) {
+            return Err("tax-rate must be a non-negative number".into());
+        }
+        if let Some((item, _)) = catalogue.prices.iter().find(|(_, price)| !valid(**price)) {
+            return Err(format!("price of `{item}` must be a non-negative amount").into());
+        }
+        Ok(catalogue)
+    }
+
+    // An order's own price wins over the catalogue's, but has to be in the invoice currency.
+    // Problems point at the order under `prefix`, where it sat before aggregation.
+    pub fn invoice(
+        &self,
+        format: Format,
+        prefix: &str,
+        receipt: Receipt,
+    ) -> Result<Invoice, Vec<Diagnostic>> {
+        let mut diagnostics = vec![];
+        let mut lines = vec![];
+        for order in receipt.orders {
+            let Some(quantity) = order.quantity else {
+                continue;
+            };
+            let path = format!("{prefix}.orders[{}]", order.index);
+            if let Some(currency) = order
+                .currency
+                .as_ref()
+                .filter(|currency| !currency.eq_ignore_ascii_case(&self.currency))
+            {
+                let message = format!(
+                    "`{}` is priced in {currency}, the invoice is in {}",
+                    order.item, self.currency
+                );
+                diagnostics.push(Diagnostic::new(format, format!("{path}.currency"), message));
+                continue;
+            }
+            let Some(price) = order
+                .price
+                .or_else(|| self.prices.get(&order.item).copied())
+            else {
+                let message = format!(
+                    "no price for `{}` in the order or the catalogue",
+                    order.item
+                );
+                diagnostics.push(Diagnostic::new(format, path, message));
+                continue;
+            };
+            let unit_price = Money::from_amount(price);
+            lines.push(InvoiceLine {
+                total: Money(unit_price.0.saturating_mul(quantity.into())),
+                item: order.item,
+                quantity,
+                unit_price,
+            });
+        }
+        if !diagnostics.is_empty() {
+            return Err(diagnostics);
+        }
+
+        let subtotal = Money(
+            lines
+                .iter()
+                .fold(0, |sum, line| sum.saturating_add(line.total.0)),
+        );
+        let tax = Money((subtotal.0 as f64 * self.tax_rate).round() as u64);
+        Ok(Invoice {
+            package: receipt.package,
+            currency: self.currency.clone(),
+            lines,
+            subtotal,
+            tax_rate: self.tax_rate,
+            tax,
+            total: Money(subtotal.0.saturating_add(tax.0)),
+            skipped: receipt.skipped,
+        })
+    }
+}
+
+impl Invoice {
+    pub fn html(&self) -> String {
+        let currency = escape(&self.currency, Html);
+        let mut rows = String::new();
+        for InvoiceLine {
+            item,
+            quantity,
+            unit_price,
+            total,
+        } in &self.lines
+        {
+            let item = escape(item, Html);
+            rows.push_str(&format!(
+                "<tr><td>{item}</td><td>{quantity}</td><td>{unit_price} {currency}</td><td>{total} {currency}</td></tr>\n"
+            ));
+        }
+        let name = escape(&self.package.name, Html);
+        let version = escape(self.package.version.as_deref().unwrap_or_default(), Html);
+        let (subtotal, tax, total) = (self.subtotal, self.tax, self.total);
+        // Rounded so that 0.07 shows as 7% and not 7.000000000000001%
+        let tax_rate = format!("{:.2}", self.tax_rate * 100.0);
+        let tax_rate = tax_rate.trim_end_matches('0').trim_end_matches('.');
+
+        format!(
+            r#"<table class="invoice">
+<caption>{name} {version}</caption>
+<thead><tr><th>Item</th><th>Quantity</th><th>Unit price</th><th>Total</th></tr></thead>
+<tbody>
+{rows}</tbody>
+<tfoot>
+<tr><td colspan="3">Subtotal</td><td>{subtotal} {currency}</td></tr>
+<tr><td colspan="3">Tax ({tax_rate}%)</td><td>{tax} {currency}</td></tr>
+<tr><td colspan="3">Total</td><td>{total} {currency}</td></tr>
+</tfoot>
+</table>
+"#
+        )
+    }
+}
@@ -0,0 +1,244 @@
+use std::{collections::BTreeMap, error::Error as StdError, fs, path::Path};
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use super::{
+    dependencies::{package_name, DependencyTable},
+    diagnostics::Diagnostic,
+    Format, Inherited, RawMetadata,
+};
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+enum PackageField {
+    Version,
+    License,
+    Repository,
+    RustVersion,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Kind {
+    String,
+    Integer,
+    Float,
+    Boolean,
+    Array,
+    Table,
+}
+
+/// The rules packages posted to `/5/manifest` have to follow, read from a TOML file.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct Policy {
+    #[serde(default)]
+    keywords: Vec<String>,
+    #[serde(default)]
+    package_fields: Vec<PackageField>,
+    #[serde(default)]
+    banned_dependencies: Vec<String>,
+    // Allowed metadata keys and their kinds, any metadata goes when unset
+    metadata: Option<BTreeMap<String, Kind>>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct RuleOutcome {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    source: Option<String>,
+    rule: &'static str,
+    passed: bool,
+}
+
+impl RuleOutcome {
+    pub fn within(mut self, source: &str) -> Self {
+        self.source = Some(source.to_owned());
+        self
+    }
+}
+
+impl PackageField {
+    fn name(self) -> &'static str {
+        match self {
+            Self::Version => "version",
+            Self::License => "license",
+            Self::Repository => "repository",
+            Self::RustVersion => "rust-version",
+        }
+    }
+
+    // Inherited fields only count when the workspace sets them
+    fn is_set(
+        self,
+        package: Option<&cargo_manifest::Package<RawMetadata>>,
+        workspace: Option<&cargo_manifest::WorkspacePackage>,
+    ) -> bool {
+        let Some(package) = package else {
+            return false;
+        };
+        let (field, inherited) = match self {
+            Self::Version => (&package.version, workspace.and_then(|w| w.version.as_ref())),
+            Self::License => (&package.license, workspace.and_then(|w| w.license.as_ref())),
+            Self::Repository => (
+                &package.repository,
+                workspace.and_then(|w| w.repository.as_ref()),
+            ),
+            Self::RustVersion => (
+                &package.rust_version,
+                workspace.and_then(|w| w.rust_version.as_ref()),
+            ),
+        };
+        field
+            .as_ref()
+            .is_some_and(|field| field.as_ref().as_local().is_some() || inherited.is_some())
+    }
+}
+
+impl Kind {
+    fn of(value: &Value) -> Option<Self> {
+        match value {
+            Value::String(_) => Some(Self::String),
+            Value::Number(n) if n.is_f64() => Some(Self::Float),
+            Value::Number(_) => Some(Self::Integer),
+            Value::Bool(_) => Some(Self::Boolean),
+            Value::Array(_) => Some(Self::Array),
+            Value::Object(_) => Some(Self::Table),
+            Value::Null => None,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Self::String => "string",
+            Self::Integer => "integer",
+            Self::Float => "float",
+            Self::Boolean => "boolean",
+            Self::Array => "array",
+            Self::Table => "table",
+        }
+    }
+}
+
+impl Policy {
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, Box<dyn StdError>> {
+        Ok(toml::from_str(&fs::read_to_string(path)?)?)
+    }
+
+    // Only the rules the policy sets are evaluated, each failure also becomes a diagnostic
+    pub fn evaluate(
+        &self,
+        format: Format,
+        package: Option<&cargo_manifest::Package<RawMetadata>>,
+        dependencies: &[DependencyTable],
+        workspace: &Inherited,
+        prefix: &str,
+        metadata: Option<&RawMetadata>,
+    ) -> (Vec<RuleOutcome>, Vec<Diagnostic>) {
+        let mut outcomes = vec![];
+        let mut diagnostics = vec![];
+        let mut rule = |rule: &'static str, problems: Vec<(String, String)>| {
+            outcomes.push(RuleOutcome {
+                source: None,
+                rule,
+                passed: problems.is_empty(),
+            });
+            diagnostics.extend(
+                problems
+                    .into_iter()
+                    .map(|(path, message)| Diagnostic::new(format, path, message).for_rule(rule)),
+            );
+        };
+        let inherited = workspace.package.as_ref();
+
+        if !self.keywords.is_empty() {
+            let keywords = package
+                .and_then(|package| package.keywords.clone())
+                .and_then(|k| k.as_local().or_else(|| inherited?.keywords.clone()))
+                .unwrap_or_default();
+            rule(
+                "keywords",
+                self.keywords
+                    .iter()
+                    .filter(|&required| !keywords.contains(required))
+                    .map(|required| {
+                        (
+                            "package.keywords".to_owned(),
+                            format!("Magic keyword not provided: {required}"),
+                        )
+                    })
+                    .collect(),
+            );
+        }
+
+        if !self.package_fields.is_empty() {
+            rule(
+                "package-fields",
+                self.package_fields
+                    .iter()
+                    .filter(|field| !field.is_set(package, inherited))
+                    .map(|field| {
+                        (
+                            format!("package.{}", field.name()),
+                            "missing required field".to_owned(),
+                        )
+                    })
+                    .collect(),
+            );
+        }
+
+        if !self.banned_dependencies.is_empty() {
+            let mut problems = vec![];
+            for table in dependencies {
+                for (key, dependency) in table.dependencies {
+                    let name = package_name(key, dependency);
+                    if self.banned_dependencies.iter().any(|banned| banned == name) {
+                        problems.push((
+                            format!("{}.{key}", table.path()),
+                            format!("dependency `{name}` is banned"),
+                        ));
+                    }
+                }
+            }
+            rule("banned-dependencies", problems);
+        }
+
+        if let Some(schema) = &self.metadata {
+            let mut problems = vec![];
+            if let Some(metadata) = metadata {
+                let orders = metadata.orders.as_ref().map(|_| ("orders", Kind::Array));
+                let rest = metadata
+                    .rest
+                    .iter()
+                    .map(|(key, value)| (key.as_str(), Kind::of(value).unwrap_or(Kind::Table)));
+                for (key, kind) in orders.into_iter().chain(rest) {
+                    match schema.get(key) {
+                        None => problems.push((
+                            format!("{prefix}.{key}"),
+                            "key not allowed by the metadata schema".to_owned(),
+                        )),
+                        Some(&expected) if expected != kind => problems.push((
+                            format!("{prefix}.{key}"),
+                            format!("expected {}, found {}", expected.name(), kind.name()),
+                        )),
+                        Some(_) => {}
+                    }
+                }
+            }
+            rule("metadata", problems);
+        }
+
+        (outcomes, diagnostics)
+    }
+}
+
+// Plain text receipts carry the policy outcomes in a header, as `rule=passed, rule=failed`
+pub fn header(outcomes: &[RuleOutcome]) -> String {
+    outcomes
+        .iter()
+        .map(|RuleOutcome { rule, passed, .. }| {
+            format!("{rule}={}", if *passed { "passed" } else { "failed" })
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
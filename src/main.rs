@@ -1,11 +1,16 @@
-use five::day_five;
+use std::{env, time::Duration};
+
+use five::{day_five, Catalogue, Policy};
 use nine::day_nine;
 use nineteen::{day_nineteen, setup_table};
 use poem::{
     endpoint::StaticFileEndpoint,
     get, handler,
-    http::{HeaderValue, StatusCode},
-    Endpoint, Response, Route,
+    http::{HeaderValue, Method, StatusCode},
+    Endpoint, EndpointExt, Response, Route,
+};
+use rate_limit::{
+    setup_table as setup_rate_limit_table, BucketSettings, RateLimit, RateLimitConfig, Trust,
 };
 use shuttle_poem::ShuttlePoem;
 use sixteen::day_sixteen;
@@ -16,6 +21,7 @@ use two::day_two;
 mod five;
 mod nine;
 mod nineteen;
+mod rate_limit;
 mod sixteen;
 mod twelve;
 mod twentythree;
@@ -38,6 +44,17 @@ fn redirect() -> Response {
     resp
 }
 
+// A comma-separated list, empty when the variable isn't set
+fn env_list(name: &str) -> Vec<String> {
+    env::var(name)
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+        .map(str::to_owned)
+        .collect()
+}
+
 #[shuttle_runtime::main]
 async fn poem(
     #[shuttle_shared_db::Postgres(
@@ -46,16 +63,48 @@ async fn poem(
     pool: sqlx::PgPool,
 ) -> ShuttlePoem<impl Endpoint> {
     setup_table(&pool).await;
+    let policy = Policy::load("./assets/manifest_policy.toml").expect("Manifest policy invalid");
+    let catalogue =
+        Catalogue::load("./assets/price_catalogue.toml").expect("Price catalogue invalid");
+    let milk = BucketSettings::load("./assets/milk_bucket.toml")
+        .and_then(|settings| settings.with_env("MILK"))
+        .expect("Milk bucket settings invalid");
+    let milk_pool = (env::var("MILK_BACKEND").as_deref() == Ok("postgres")).then(|| pool.clone());
+    let milk_admin = env::var("MILK_ADMIN_TOKEN")
+        .ok()
+        .filter(|token| !token.is_empty());
+    if milk_pool.is_some() {
+        setup_rate_limit_table(&pool).await;
+    }
+    let proxies = env_list("TRUSTED_PROXIES")
+        .iter()
+        .map(|proxy| proxy.parse())
+        .collect::<Result<Vec<_>, _>>()
+        .expect("Trusted proxy addresses invalid");
+    let trust = Trust::default()
+        .proxies(proxies)
+        .api_keys(env_list("MILK_API_KEYS"));
+    let writes = [Method::POST, Method::PUT, Method::DELETE];
+    let quote_writes =
+        RateLimit::new(RateLimitConfig::new(10, Duration::from_secs(6)).methods(writes));
+    let tokens = RateLimit::new(
+        RateLimitConfig::new(20, Duration::from_secs(1))
+            .refill(5)
+            .key({
+                let trust = trust.clone();
+                move |req| trust.address(req)
+            }),
+    );
 
     let app = Route::new()
         .at("/", get(hello_world))
         .at("/-1/seek", get(redirect))
         .nest("/2", day_two())
-        .nest("/5", day_five())
-        .nest("/9", day_nine())
+        .nest("/5", day_five(policy, catalogue))
+        .nest("/9", day_nine(milk, milk_pool, milk_admin, trust))
         .nest("/12", day_twelve())
-        .nest("/16", day_sixteen())
-        .nest("/19", day_nineteen(pool))
+        .nest("/16", day_sixteen().with(tokens))
+        .nest("/19", day_nineteen(pool).with(quote_writes))
         .nest("/23", day_twentythree())
         .nest(
             "/assets/23.html",
@@ -1,15 +1,21 @@
-use std::{sync::Arc, time::Duration};
+use std::net::IpAddr;
 
-use leaky_bucket::RateLimiter;
 use poem::{
-    handler,
+    get, handler,
     http::StatusCode,
     post,
-    web::{headers::ContentType, Data, Json, TypedHeader},
-    EndpointExt, IntoEndpoint, Response, Route,
+    web::{
+        headers::{authorization::Bearer, Authorization, ContentType},
+        Data, Json, Query, TypedHeader,
+    },
+    EndpointExt, IntoEndpoint, IntoResponse, Response, Result, Route,
 };
 use serde::{Deserialize, Serialize};
-use tokio::sync::RwLock;
+use sqlx::PgPool;
+
+use crate::rate_limit::{
+    BucketSettings, Client, RateLimit, RateLimitConfig, ReconfigureError, Trust,
+};
 
 #[derive(Deserialize, Serialize)]
 #[serde(rename_all = "lowercase")]
@@ -31,25 +37,36 @@ impl Metric {
     }
 }
 
-fn fresh_rates() -> RateLimiter {
-    RateLimiter::builder()
-        .initial(5)
-        .max(5)
-        .interval(Duration::from_secs(1))
-        .build()
+// The bearer token `PUT /config` asks for, without one nobody may change the settings
+#[derive(Clone)]
+struct AdminToken(Option<String>);
+
+impl AdminToken {
+    // Looks at every byte, so that the time taken doesn't tell how much of a guess was right
+    fn accepts(&self, given: &str) -> bool {
+        self.0.as_ref().is_some_and(|token| {
+            token.len() == given.len()
+                && token
+                    .bytes()
+                    .zip(given.bytes())
+                    .fold(0, |diff, (a, b)| diff | (a ^ b))
+                    == 0
+        })
+    }
+}
+
+#[derive(Deserialize)]
+struct RefillTarget {
+    api_key: Option<String>,
+    ip: Option<IpAddr>,
 }
 
 #[handler]
 async fn milk(
-    Data(rate_limiter): Data<&Arc<RwLock<RateLimiter>>>,
     content_type: Option<TypedHeader<ContentType>>,
     body: Option<Json<Metric>>,
 ) -> Response {
-    let response = if rate_limiter.read().await.try_acquire(1) {
-        (StatusCode::OK, "Milk withdrawn\n").into()
-    } else {
-        return (StatusCode::TOO_MANY_REQUESTS, "No milk available\n").into();
-    };
+    let response = (StatusCode::OK, "Milk withdrawn\n").into();
 
     match content_type {
         Some(TypedHeader(content_type)) if content_type == ContentType::json() => {
@@ -64,16 +81,274 @@ async fn milk(
     }
 }
 
+// Refills one client's bucket given `api_key` or `ip`, or every bucket without either
+#[handler]
+async fn refill(
+    Data(limit): Data<&RateLimit>,
+    Query(RefillTarget { api_key, ip }): Query<RefillTarget>,
+) -> Response {
+    let client = match (api_key, ip) {
+        (Some(key), None) => Some(Client::ApiKey(key)),
+        (None, Some(ip)) => Some(Client::Ip(ip)),
+        (None, None) => None,
+        (Some(_), Some(_)) => return StatusCode::BAD_REQUEST.into(),
+    };
+    match limit.refill(client.as_ref()).await {
+        Ok(()) => StatusCode::OK.into(),
+        Err(e) => {
+            eprintln!("Couldn't refill shared buckets: {e}");
+            StatusCode::INTERNAL_SERVER_ERROR.into()
+        }
+    }
+}
+
+#[handler]
+async fn show_config(Data(limit): Data<&RateLimit>) -> Json<BucketSettings> {
+    Json(limit.settings().await)
+}
+
 #[handler]
-async fn refill(Data(rate_limiter): Data<&Arc<RwLock<RateLimiter>>>) -> Response {
-    let mut guard = rate_limiter.write().await;
-    *guard = fresh_rates();
-    StatusCode::OK.into()
+async fn reconfigure(
+    Data(limit): Data<&RateLimit>,
+    Data(admin): Data<&AdminToken>,
+    auth: Option<TypedHeader<Authorization<Bearer>>>,
+    settings: Result<Json<BucketSettings>>,
+) -> Result<Response> {
+    if admin.0.is_none() {
+        return Ok((StatusCode::FORBIDDEN, "No admin token configured\n").into_response());
+    }
+    if !auth.is_some_and(|TypedHeader(auth)| admin.accepts(auth.token())) {
+        return Ok(StatusCode::UNAUTHORIZED
+            .with_header("WWW-Authenticate", "Bearer")
+            .into_response());
+    }
+    let Json(settings) = settings?;
+    Ok(match limit.reconfigure(settings).await {
+        Ok(()) => Json(settings).into_response(),
+        Err(e @ ReconfigureError::Shared) => {
+            (StatusCode::CONFLICT, format!("{e}\n")).into_response()
+        }
+        Err(e) => (StatusCode::BAD_REQUEST, format!("{e}\n")).into_response(),
+    })
 }
 
-pub fn day_nine() -> impl IntoEndpoint {
+// With a pool, every instance draws from the same milk bucket and `/config` is read-only.
+// Without an admin token, it is read-only as well. `trust` tells clients apart.
+pub fn day_nine(
+    settings: BucketSettings,
+    pool: Option<PgPool>,
+    admin_token: Option<String>,
+    trust: Trust,
+) -> impl IntoEndpoint {
+    let mut config = RateLimitConfig::with_settings(settings)
+        .message("No milk available\n")
+        .key(move |req| trust.client(req));
+    if let Some(pool) = pool {
+        config = config.postgres(pool, "milk");
+    }
+    let limit = RateLimit::new(config);
     Route::new()
-        .at("/milk", post(milk))
+        .at("/milk", post(milk).with(limit.clone()))
         .at("/refill", post(refill))
-        .data(Arc::new(RwLock::new(fresh_rates())))
+        .at("/config", get(show_config).put(reconfigure))
+        .data(limit)
+        .data(AdminToken(admin_token))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use poem::test::TestClient;
+
+    use super::*;
+
+    const TOKEN: &str = "milkman";
+    const BEARER: &str = "Bearer milkman";
+
+    const MILK: BucketSettings = BucketSettings {
+        initial: 5,
+        max: 5,
+        interval_ms: 1000,
+        refill: 1,
+    };
+
+    fn trust() -> Trust {
+        Trust::default().api_keys(["a", "noisy", "quiet", "load-test"])
+    }
+
+    async fn withdraw(cli: &TestClient<impl poem::Endpoint>, header: (&str, &str)) -> StatusCode {
+        cli.post("/milk")
+            .header(header.0, header.1)
+            .send()
+            .await
+            .0
+            .status()
+    }
+
+    #[tokio::test]
+    async fn buckets_per_client() {
+        let cli = TestClient::new(day_nine(MILK, None, None, trust()).into_endpoint());
+        let noisy = ("X-Api-Key", "noisy");
+        for _ in 0..5 {
+            assert_eq!(withdraw(&cli, noisy).await, StatusCode::OK);
+        }
+        assert_eq!(withdraw(&cli, noisy).await, StatusCode::TOO_MANY_REQUESTS);
+
+        assert_eq!(withdraw(&cli, ("X-Api-Key", "quiet")).await, StatusCode::OK);
+
+        // Made-up keys and forwarded addresses from untrusted peers don't get a fresh bucket
+        for index in 0..5 {
+            let forwarded = format!("203.0.113.{index}");
+            assert_eq!(
+                withdraw(&cli, ("X-Forwarded-For", &forwarded)).await,
+                StatusCode::OK
+            );
+        }
+        let made_up = ("X-Api-Key", "made-up");
+        assert_eq!(withdraw(&cli, made_up).await, StatusCode::TOO_MANY_REQUESTS);
+
+        cli.post("/refill")
+            .query("api_key", &"noisy")
+            .send()
+            .await
+            .assert_status_is_ok();
+        assert_eq!(withdraw(&cli, noisy).await, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn rate_limit_headers() {
+        let cli = TestClient::new(day_nine(MILK, None, None, trust()).into_endpoint());
+        let resp = cli.post("/milk").header("X-Api-Key", "a").send().await;
+        resp.assert_status_is_ok();
+        resp.assert_header("RateLimit-Limit", "5");
+        resp.assert_header("RateLimit-Remaining", "4");
+        resp.assert_header("RateLimit-Reset", "1");
+        resp.assert_header("Retry-After", "0");
+
+        for _ in 0..4 {
+            cli.post("/milk").header("X-Api-Key", "a").send().await;
+        }
+        let resp = cli.post("/milk").header("X-Api-Key", "a").send().await;
+        resp.assert_status(StatusCode::TOO_MANY_REQUESTS);
+        resp.assert_header("RateLimit-Remaining", "0");
+        resp.assert_header("RateLimit-Reset", "5");
+        resp.assert_header("Retry-After", "1");
+    }
+
+    #[tokio::test]
+    async fn refills_between_requests() {
+        let fast = BucketSettings {
+            interval_ms: 200,
+            ..MILK
+        };
+        let cli =
+            TestClient::new(day_nine(fast, None, Some(TOKEN.into()), trust()).into_endpoint());
+        for _ in 0..3 {
+            cli.post("/milk").header("X-Api-Key", "a").send().await;
+        }
+
+        // Two intervals bring back two of the three tokens taken
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        let resp = cli.post("/milk").header("X-Api-Key", "a").send().await;
+        resp.assert_status_is_ok();
+        resp.assert_header("RateLimit-Remaining", "3");
+
+        // Refills due before a reconfiguration are kept
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        cli.put("/config")
+            .header("Authorization", BEARER)
+            .body_json(&fast)
+            .send()
+            .await;
+        let resp = cli.post("/milk").header("X-Api-Key", "a").send().await;
+        resp.assert_header("RateLimit-Remaining", "4");
+    }
+
+    #[tokio::test]
+    async fn reconfigure_live() {
+        let cli =
+            TestClient::new(day_nine(MILK, None, Some(TOKEN.into()), trust()).into_endpoint());
+        let client = ("X-Api-Key", "load-test");
+        for _ in 0..3 {
+            assert_eq!(withdraw(&cli, client).await, StatusCode::OK);
+        }
+
+        let bigger = BucketSettings { max: 50, ..MILK };
+        let resp = cli
+            .put("/config")
+            .header("Authorization", BEARER)
+            .body_json(&bigger)
+            .send()
+            .await;
+        resp.assert_status_is_ok();
+        cli.get("/config").send().await.assert_json(&bigger).await;
+
+        // The two tokens left carry over, the client doesn't get a fresh bucket
+        let resp = cli.post("/milk").header(client.0, client.1).send().await;
+        resp.assert_header("RateLimit-Limit", "50");
+        resp.assert_header("RateLimit-Remaining", "1");
+
+        let broken = BucketSettings { initial: 6, ..MILK };
+        let resp = cli
+            .put("/config")
+            .header("Authorization", BEARER)
+            .body_json(&broken)
+            .send()
+            .await;
+        resp.assert_status(StatusCode::BAD_REQUEST);
+        cli.get("/config").send().await.assert_json(&bigger).await;
+    }
+
+    #[tokio::test]
+    async fn config_needs_the_admin_token() {
+        let bigger = BucketSettings { max: 50, ..MILK };
+        let cli =
+            TestClient::new(day_nine(MILK, None, Some(TOKEN.into()), trust()).into_endpoint());
+        let resp = cli.put("/config").body_json(&bigger).send().await;
+        resp.assert_status(StatusCode::UNAUTHORIZED);
+        resp.assert_header("WWW-Authenticate", "Bearer");
+        let resp = cli
+            .put("/config")
+            .header("Authorization", "Bearer milkmaid")
+            .body_json(&bigger)
+            .send()
+            .await;
+        resp.assert_status(StatusCode::UNAUTHORIZED);
+        let resp = cli
+            .put("/config")
+            .header("Authorization", BEARER)
+            .content_type("application/json")
+            .body("{")
+            .send()
+            .await;
+        resp.assert_status(StatusCode::BAD_REQUEST);
+        cli.get("/config").send().await.assert_json(&MILK).await;
+
+        let cli = TestClient::new(day_nine(MILK, None, None, trust()).into_endpoint());
+        let resp = cli
+            .put("/config")
+            .header("Authorization", BEARER)
+            .body_json(&bigger)
+            .send()
+            .await;
+        resp.assert_status(StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn shared_config_is_read_only() {
+        let pool = PgPool::connect_lazy("postgres://localhost/milk").unwrap();
+        let cli = TestClient::new(
+            day_nine(MILK, Some(pool), Some(TOKEN.into()), trust()).into_endpoint(),
+        );
+        let bigger = BucketSettings { max: 50, ..MILK };
+        let resp = cli
+            .put("/config")
+            .header("Authorization", BEARER)
+            .body_json(&bigger)
+            .send()
+            .await;
+        resp.assert_status(StatusCode::CONFLICT);
+        cli.get("/config").send().await.assert_json(&MILK).await;
+    }
 }
@@ -0,0 +1,834 @@
+use std::{
+    collections::{HashMap, HashSet},
+    env,
+    error::Error as StdError,
+    fmt, fs,
+    net::IpAddr,
+    path::Path,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use leaky_bucket::RateLimiter;
+use poem::{
+    http::{header::RETRY_AFTER, Method, StatusCode},
+    Endpoint, IntoResponse, Middleware, Request, Response, Result,
+};
+use serde::{Deserialize, Serialize};
+use sqlx::{
+    types::chrono::{DateTime, Utc},
+    PgPool,
+};
+use tokio::sync::RwLock;
+
+/// Creates the table shared buckets live in, see [`RateLimitConfig::postgres`].
+pub async fn setup_table(pool: &PgPool) {
+    if let Err(e) = sqlx::query(
+        r#"CREATE TABLE IF NOT EXISTS rate_limits (
+        name TEXT NOT NULL,
+        client TEXT NOT NULL,
+        tokens BIGINT NOT NULL,
+        refilled_at TIMESTAMPTZ NOT NULL,
+        PRIMARY KEY (name, client)
+        )"#,
+    )
+    .execute(pool)
+    .await
+    {
+        eprintln!("Couldn't create rate limit table: {e}");
+    }
+}
+
+/// Who a bucket belongs to. Clients that can't be told apart share the `Unknown` one.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Client {
+    ApiKey(String),
+    Ip(IpAddr),
+    Unknown,
+}
+
+impl Client {
+    /// The default key: the address the request came from, whatever its headers say.
+    pub fn by_peer(req: &Request) -> Self {
+        Trust::default().address(req)
+    }
+}
+
+/// The proxies allowed to name the client in `X-Forwarded-For`, and the `X-Api-Key`s that
+/// exist. Anyone can send these headers, so from everyone else they're ignored.
+#[derive(Debug, Clone, Default)]
+pub struct Trust {
+    proxies: HashSet<IpAddr>,
+    api_keys: HashSet<String>,
+}
+
+impl Trust {
+    pub fn proxies(mut self, proxies: impl IntoIterator<Item = IpAddr>) -> Self {
+        self.proxies.extend(proxies);
+        self
+    }
+
+    pub fn api_keys(mut self, keys: impl IntoIterator<Item = impl Into<String>>) -> Self {
+        self.api_keys.extend(keys.into_iter().map(Into::into));
+        self
+    }
+
+    /// A known `X-Api-Key` wins over the address, see [`Trust::address`].
+    pub fn client(&self, req: &Request) -> Client {
+        match req.header("X-Api-Key") {
+            Some(key) if self.api_keys.contains(key) => Client::ApiKey(key.to_owned()),
+            _ => self.address(req),
+        }
+    }
+
+    /// The peer address, or the one a trusted proxy forwarded the request for.
+    pub fn address(&self, req: &Request) -> Client {
+        let peer = req
+            .remote_addr()
+            .as_socket_addr()
+            .map(|address| address.ip());
+        let forwarded = req
+            .headers()
+            .get_all("X-Forwarded-For")
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .collect::<Vec<_>>()
+            .join(",");
+        match self.forwarded_for(peer, &forwarded) {
+            Some(address) => Client::Ip(address),
+            None => Client::Unknown,
+        }
+    }
+
+    // Every proxy appends the address it got the request from, so the list is read from the
+    // right until an address that isn't a trusted proxy
+    fn forwarded_for(&self, peer: Option<IpAddr>, forwarded: &str) -> Option<IpAddr> {
+        let mut address = peer?;
+        let mut hops = forwarded.rsplit(',').map(str::trim);
+        while self.proxies.contains(&address) {
+            let Some(hop) = hops.next().and_then(|hop| hop.parse().ok()) else {
+                break;
+            };
+            address = hop;
+        }
+        Some(address)
+    }
+}
+
+/// The shape of every bucket a [`RateLimit`] hands out.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct BucketSettings {
+    pub initial: usize,
+    pub max: usize,
+    pub interval_ms: u64,
+    pub refill: usize,
+}
+
+#[derive(Debug)]
+pub struct InvalidSettings(&'static str);
+
+impl fmt::Display for InvalidSettings {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid bucket settings: {}", self.0)
+    }
+}
+
+impl StdError for InvalidSettings {}
+
+#[derive(Debug)]
+pub enum ReconfigureError {
+    Invalid(InvalidSettings),
+    /// The buckets live in Postgres, where other instances would keep using the old settings.
+    Shared,
+}
+
+impl fmt::Display for ReconfigureError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Invalid(e) => e.fmt(f),
+            Self::Shared => f.write_str("shared buckets can't be reconfigured at runtime"),
+        }
+    }
+}
+
+impl StdError for ReconfigureError {}
+
+impl From<InvalidSettings> for ReconfigureError {
+    fn from(e: InvalidSettings) -> Self {
+        Self::Invalid(e)
+    }
+}
+
+impl BucketSettings {
+    /// Reads the settings from a TOML file.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, Box<dyn StdError>> {
+        let settings: Self = toml::from_str(&fs::read_to_string(path)?)?;
+        Ok(settings.validate()?)
+    }
+
+    /// Overrides the settings given in `{prefix}_INITIAL`, `{prefix}_MAX`,
+    /// `{prefix}_INTERVAL_MS` and `{prefix}_REFILL`.
+    pub fn with_env(mut self, prefix: &str) -> Result<Self, Box<dyn StdError>> {
+        fn var<T: FromStr>(name: String, field: &mut T) -> Result<(), Box<dyn StdError>>
+        where
+            T::Err: StdError + 'static,
+        {
+            if let Ok(value) = env::var(&name) {
+                *field = value.parse().map_err(|e| format!("{name}: {e}"))?;
+            }
+            Ok(())
+        }
+        var(format!("{prefix}_INITIAL"), &mut self.initial)?;
+        var(format!("{prefix}_MAX"), &mut self.max)?;
+        var(format!("{prefix}_INTERVAL_MS"), &mut self.interval_ms)?;
+        var(format!("{prefix}_REFILL"), &mut self.refill)?;
+        Ok(self.validate()?)
+    }
+
+    /// Rejects settings that would leave a bucket without tokens or refills.
+    pub fn validate(self) -> Result<Self, InvalidSettings> {
+        if self.max == 0 || self.refill == 0 {
+            return Err(InvalidSettings("max and refill have to be at least 1"));
+        }
+        // The limiter counts intervals in whole milliseconds
+        if self.interval_ms == 0 {
+            return Err(InvalidSettings("interval_ms has to be at least 1"));
+        }
+        if self.initial > self.max {
+            return Err(InvalidSettings("initial can't be more than max"));
+        }
+        Ok(self)
+    }
+
+    fn interval(&self) -> Duration {
+        Duration::from_millis(self.interval_ms)
+    }
+
+    fn limiter(&self, initial: usize) -> RateLimiter {
+        RateLimiter::builder()
+            .initial(initial)
+            .max(self.max)
+            .refill(self.refill)
+            .interval(self.interval())
+            .build()
+    }
+
+    // The tokens after the refills `elapsed` covers, and how far into the next interval it is
+    fn refilled(&self, tokens: usize, elapsed: Duration) -> (usize, Duration) {
+        let interval = self.interval().as_nanos();
+        let periods = usize::try_from(elapsed.as_nanos() / interval).unwrap_or(usize::MAX);
+        let into_period = Duration::from_nanos((elapsed.as_nanos() % interval) as u64);
+        let tokens = tokens.saturating_add(periods.saturating_mul(self.refill));
+        (tokens.min(self.max), into_period)
+    }
+
+    // A bucket idle this long has refilled completely, so dropping it loses nothing
+    fn idle_ttl(&self) -> Duration {
+        let refills = self.max.div_ceil(self.refill) as u32;
+        self.interval() * refills.max(1)
+    }
+}
+
+impl fmt::Display for Client {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::ApiKey(key) => write!(f, "key:{key}"),
+            Self::Ip(address) => write!(f, "ip:{address}"),
+            Self::Unknown => f.write_str("unknown"),
+        }
+    }
+}
+
+type KeyExtractor = Arc<dyn Fn(&Request) -> Client + Send + Sync>;
+
+/// How many requests a client gets, how fast they come back and who counts as a client.
+#[derive(Clone)]
+pub struct RateLimitConfig {
+    settings: BucketSettings,
+    methods: Option<Vec<Method>>,
+    message: &'static str,
+    key: KeyExtractor,
+    max_clients: usize,
+    postgres: Option<(PgPool, &'static str)>,
+}
+
+impl RateLimitConfig {
+    /// A full bucket of `capacity` tokens that gains one back every `interval`.
+    pub fn new(capacity: usize, interval: Duration) -> Self {
+        Self::with_settings(BucketSettings {
+            initial: capacity,
+            max: capacity,
+            interval_ms: interval.as_millis() as u64,
+            refill: 1,
+        })
+    }
+
+    pub fn with_settings(settings: BucketSettings) -> Self {
+        Self {
+            settings,
+            methods: None,
+            message: "Too many requests\n",
+            key: Arc::new(Client::by_peer),
+            max_clients: 10_000,
+            postgres: None,
+        }
+    }
+
+    /// Tokens that come back every interval.
+    pub fn refill(mut self, refill: usize) -> Self {
+        self.settings.refill = refill;
+        self
+    }
+
+    /// Only limits requests with these methods, everything else passes for free.
+    pub fn methods(mut self, methods: impl IntoIterator<Item = Method>) -> Self {
+        self.methods = Some(methods.into_iter().collect());
+        self
+    }
+
+    /// The body of 429 responses.
+    pub fn message(mut self, message: &'static str) -> Self {
+        self.message = message;
+        self
+    }
+
+    /// Picks the bucket a request draws from, [`Client::by_peer`] by default. See [`Trust`]
+    /// for keys that read headers.
+    pub fn key(mut self, key: impl Fn(&Request) -> Client + Send + Sync + 'static) -> Self {
+        self.key = Arc::new(key);
+        self
+    }
+
+    /// How many clients get a bucket of their own in memory, 10 000 by default. Past that,
+    /// new clients share the `Unknown` bucket until idle ones are dropped.
+    pub fn max_clients(mut self, max_clients: usize) -> Self {
+        self.max_clients = max_clients;
+        self
+    }
+
+    /// Keeps the buckets in Postgres under `name`, so that every instance using the name
+    /// draws from the same ones. Instances have to agree on the settings, so
+    /// [`RateLimit::reconfigure`] refuses to change them.
+    pub fn postgres(mut self, pool: PgPool, name: &'static str) -> Self {
+        self.postgres = Some((pool, name));
+        self
+    }
+}
+
+struct Bucket {
+    limiter: RateLimiter,
+    // The limiter refills on whole intervals counted from when it was built
+    created: Instant,
+    last_seen: Instant,
+}
+
+// A client's bucket right after a withdrawal, as told in the rate limit headers
+struct Quota {
+    acquired: bool,
+    limit: usize,
+    remaining: usize,
+    // Until the bucket is full again
+    reset: Duration,
+    // Until the next token, zero while tokens are left
+    retry_after: Duration,
+}
+
+impl Bucket {
+    fn new(settings: &BucketSettings, tokens: usize, now: Instant) -> Self {
+        Self {
+            limiter: settings.limiter(tokens),
+            created: now,
+            last_seen: now,
+        }
+    }
+
+    // The limiter only adds the refills due when it's asked for tokens. Asking for more than
+    // it can ever hold never succeeds, but brings the balance up to date.
+    fn balance(&self) -> usize {
+        self.limiter
+            .try_acquire(self.limiter.max().saturating_add(1));
+        self.limiter.balance()
+    }
+
+    fn try_acquire(&self, now: Instant, settings: &BucketSettings) -> Quota {
+        let acquired = self.limiter.try_acquire(1);
+        let interval = settings.interval().as_nanos();
+        let into_period = now.duration_since(self.created).as_nanos() % interval;
+        let into_period = Duration::from_nanos(into_period as u64);
+        Quota::new(settings, acquired, self.limiter.balance(), into_period)
+    }
+}
+
+impl Quota {
+    fn new(
+        settings: &BucketSettings,
+        acquired: bool,
+        remaining: usize,
+        into_period: Duration,
+    ) -> Self {
+        let interval = settings.interval();
+        let next_refill = interval - into_period;
+        let refills = (settings.max - remaining).div_ceil(settings.refill) as u32;
+        Self {
+            acquired,
+            limit: settings.max,
+            remaining,
+            reset: match refills {
+                0 => Duration::ZERO,
+                refills => next_refill + interval * (refills - 1),
+            },
+            retry_after: if remaining > 0 {
+                Duration::ZERO
+            } else {
+                next_refill
+            },
+        }
+    }
+
+    fn apply(&self, mut response: Response) -> Response {
+        // Partial seconds round up, so that clients never come back too early
+        let seconds = |duration: Duration| duration.as_secs_f64().ceil() as u64;
+        let headers = response.headers_mut();
+        headers.insert("RateLimit-Limit", self.limit.into());
+        headers.insert("RateLimit-Remaining", self.remaining.into());
+        headers.insert("RateLimit-Reset", seconds(self.reset).into());
+        headers.insert(RETRY_AFTER, seconds(self.retry_after).into());
+        response
+    }
+}
+
+struct Buckets {
+    settings: BucketSettings,
+    clients: HashMap<Client, Bucket>,
+    max_clients: usize,
+    swept: Instant,
+}
+
+impl Buckets {
+    fn try_acquire(&mut self, client: Client) -> Quota {
+        let now = Instant::now();
+        let settings = self.settings;
+        let idle_ttl = settings.idle_ttl();
+        if now.duration_since(self.swept) >= idle_ttl {
+            self.clients
+                .retain(|_, bucket| now.duration_since(bucket.last_seen) < idle_ttl);
+            self.swept = now;
+        }
+        // Past the cap new clients share a bucket, so made-up keys can't grow the map
+        let full = self.clients.len() >= self.max_clients;
+        let client = if full && !self.clients.contains_key(&client) {
+            Client::Unknown
+        } else {
+            client
+        };
+        let bucket = self
+            .clients
+            .entry(client)
+            .or_insert_with(|| Bucket::new(&settings, settings.initial, now));
+        bucket.last_seen = now;
+        bucket.try_acquire(now, &settings)
+    }
+}
+
+// Buckets kept in Postgres, each withdrawal locks its row until the new balance is written
+struct Shared {
+    pool: PgPool,
+    name: &'static str,
+    swept: Mutex<Instant>,
+    // Set while requests fall back to this instance's buckets, so that it's only told once
+    unreachable: AtomicBool,
+}
+
+impl Shared {
+    async fn try_acquire(
+        &self,
+        client: &Client,
+        settings: &BucketSettings,
+    ) -> Result<Quota, sqlx::Error> {
+        self.sweep(settings).await?;
+
+        let client = client.to_string();
+        let mut tx = self.pool.begin().await?;
+        sqlx::query(
+            "INSERT INTO rate_limits (name, client, tokens, refilled_at) VALUES ($1, $2, $3, now())
+            ON CONFLICT (name, client) DO NOTHING",
+        )
+        .bind(self.name)
+        .bind(&client)
+        .bind(settings.initial as i64)
+        .execute(&mut *tx)
+        .await?;
+        // The database's clock, so that replicas agree on how much has refilled
+        let (tokens, refilled_at, now): (i64, DateTime<Utc>, DateTime<Utc>) = sqlx::query_as(
+            "SELECT tokens, refilled_at, now() FROM rate_limits
+            WHERE name = $1 AND client = $2 FOR UPDATE",
+        )
+        .bind(self.name)
+        .bind(&client)
+        .fetch_one(&mut *tx)
+        .await?;
+
+        let elapsed = (now - refilled_at).to_std().unwrap_or_default();
+        let (tokens, into_period) = settings.refilled(tokens.max(0) as usize, elapsed);
+        let acquired = tokens > 0;
+        let remaining = tokens - usize::from(acquired);
+        // Refills stay on whole intervals counted from when the row was created
+        sqlx::query(
+            "UPDATE rate_limits SET tokens = $3, refilled_at = $4 - make_interval(secs => $5)
+            WHERE name = $1 AND client = $2",
+        )
+        .bind(self.name)
+        .bind(&client)
+        .bind(remaining as i64)
+        .bind(now)
+        .bind(into_period.as_secs_f64())
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+
+        Ok(Quota::new(settings, acquired, remaining, into_period))
+    }
+
+    // Every withdrawal moves `refilled_at` to within an interval of it, so rows behind by
+    // more than the idle TTL have been full for a while
+    async fn sweep(&self, settings: &BucketSettings) -> Result<(), sqlx::Error> {
+        let idle_ttl = settings.idle_ttl();
+        {
+            let mut swept = self.swept.lock().unwrap();
+            if swept.elapsed() < idle_ttl {
+                return Ok(());
+            }
+            *swept = Instant::now();
+        }
+        sqlx::query(
+            "DELETE FROM rate_limits WHERE name = $1 AND refilled_at < now() - make_interval(secs => $2)",
+        )
+        .bind(self.name)
+        .bind((idle_ttl + settings.interval()).as_secs_f64())
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn refill(&self, client: Option<&Client>) -> Result<(), sqlx::Error> {
+        match client {
+            Some(client) => {
+                sqlx::query("DELETE FROM rate_limits WHERE name = $1 AND client = $2")
+                    .bind(self.name)
+                    .bind(client.to_string())
+                    .execute(&self.pool)
+                    .await?
+            }
+            None => {
+                sqlx::query("DELETE FROM rate_limits WHERE name = $1")
+                    .bind(self.name)
+                    .execute(&self.pool)
+                    .await?
+            }
+        };
+        Ok(())
+    }
+}
+
+/// Middleware that gives every client its own leaky bucket and answers with 429 once it's
+/// empty. Every limited response carries `RateLimit-*` and `Retry-After` headers.
+///
+/// Clones share their buckets, so a clone kept aside can refill the ones in use.
+#[derive(Clone)]
+pub struct RateLimit {
+    config: Arc<RateLimitConfig>,
+    buckets: Arc<RwLock<Buckets>>,
+    shared: Option<Arc<Shared>>,
+}
+
+impl RateLimit {
+    /// # Panics
+    ///
+    /// If the settings don't pass [`BucketSettings::validate`], like a zero refill or an
+    /// interval under a millisecond.
+    pub fn new(config: RateLimitConfig) -> Self {
+        if let Err(e) = config.settings.validate() {
+            panic!("RateLimit::new: {e}");
+        }
+        Self {
+            buckets: Arc::new(RwLock::new(Buckets {
+                settings: config.settings,
+                clients: HashMap::new(),
+                max_clients: config.max_clients,
+                swept: Instant::now(),
+            })),
+            shared: config.postgres.clone().map(|(pool, name)| {
+                Arc::new(Shared {
+                    pool,
+                    name,
+                    swept: Mutex::new(Instant::now()),
+                    unreachable: AtomicBool::new(false),
+                })
+            }),
+            config: Arc::new(config),
+        }
+    }
+
+    pub async fn settings(&self) -> BucketSettings {
+        self.buckets.read().await.settings
+    }
+
+    /// Switches every bucket to new settings. Clients keep the tokens they have, up to the
+    /// new max, and requests queued behind the change go on with the new settings.
+    ///
+    /// Buckets kept in Postgres can't be reconfigured, the other instances sharing them
+    /// wouldn't hear of it. Their settings come from each instance's startup config.
+    pub async fn reconfigure(&self, settings: BucketSettings) -> Result<(), ReconfigureError> {
+        if self.shared.is_some() {
+            return Err(ReconfigureError::Shared);
+        }
+        let settings = settings.validate()?;
+        let mut buckets = self.buckets.write().await;
+        let now = Instant::now();
+        for bucket in buckets.clients.values_mut() {
+            // Refills due under the old settings are counted before the limiter is rebuilt
+            let last_seen = bucket.last_seen;
+            *bucket = Bucket {
+                last_seen,
+                ..Bucket::new(&settings, bucket.balance().min(settings.max), now)
+            };
+        }
+        buckets.settings = settings;
+        Ok(())
+    }
+
+    /// Refills one client's bucket, or every bucket without a client.
+    pub async fn refill(&self, client: Option<&Client>) -> Result<(), sqlx::Error> {
+        // A dropped bucket comes back full on the client's next request
+        match client {
+            Some(client) => {
+                self.buckets.write().await.clients.remove(client);
+            }
+            None => self.buckets.write().await.clients.clear(),
+        }
+        if let Some(shared) = &self.shared {
+            shared.refill(client).await?;
+        }
+        Ok(())
+    }
+
+    async fn try_acquire(&self, client: Client) -> Quota {
+        if let Some(shared) = &self.shared {
+            let settings = self.settings().await;
+            match shared.try_acquire(&client, &settings).await {
+                Ok(quota) => {
+                    if shared.unreachable.swap(false, Ordering::Relaxed) {
+                        eprintln!("Shared rate limits are back");
+                    }
+                    return quota;
+                }
+                // Limiting each instance on its own beats not limiting at all
+                Err(e) => {
+                    if !shared.unreachable.swap(true, Ordering::Relaxed) {
+                        eprintln!("Couldn't reach shared rate limits, limiting per instance: {e}");
+                    }
+                }
+            }
+        }
+        self.buckets.write().await.try_acquire(client)
+    }
+}
+
+impl<E: Endpoint> Middleware<E> for RateLimit {
+    type Output = RateLimitEndpoint<E>;
+
+    fn transform(&self, inner: E) -> Self::Output {
+        RateLimitEndpoint {
+            inner,
+            limit: self.clone(),
+        }
+    }
+}
+
+pub struct RateLimitEndpoint<E> {
+    inner: E,
+    limit: RateLimit,
+}
+
+impl<E: Endpoint> Endpoint for RateLimitEndpoint<E> {
+    type Output = Response;
+
+    async fn call(&self, req: Request) -> Result<Self::Output> {
+        let config = &self.limit.config;
+        if config
+            .methods
+            .as_ref()
+            .is_some_and(|methods| !methods.contains(req.method()))
+        {
+            return self.inner.call(req).await.map(IntoResponse::into_response);
+        }
+
+        let client = (config.key)(&req);
+        let quota = self.limit.try_acquire(client).await;
+        if !quota.acquired {
+            return Ok(quota.apply((StatusCode::TOO_MANY_REQUESTS, config.message).into_response()));
+        }
+        let response = match self.inner.call(req).await {
+            Ok(response) => response.into_response(),
+            Err(e) => e.into_response(),
+        };
+        Ok(quota.apply(response))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use poem::{get, handler, test::TestClient, EndpointExt};
+    use sqlx::postgres::PgPoolOptions;
+
+    use super::*;
+
+    #[handler]
+    fn hello() -> &'static str {
+        "Hello"
+    }
+
+    #[tokio::test]
+    async fn limits_only_listed_methods() {
+        let limit = RateLimit::new(
+            RateLimitConfig::new(1, Duration::from_secs(60))
+                .methods([Method::POST])
+                .key(|_| Client::Unknown),
+        );
+        let cli = TestClient::new(get(hello).post(hello).with(limit.clone()));
+
+        cli.post("/").send().await.assert_status_is_ok();
+        let resp = cli.post("/").send().await;
+        resp.assert_status(StatusCode::TOO_MANY_REQUESTS);
+        resp.assert_header("Retry-After", "60");
+        resp.assert_text("Too many requests\n").await;
+
+        let resp = cli.get("/").send().await;
+        resp.assert_status_is_ok();
+        resp.assert_header_is_not_exist("RateLimit-Limit");
+
+        limit.refill(Some(&Client::Unknown)).await.unwrap();
+        cli.post("/").send().await.assert_status_is_ok();
+    }
+
+    #[tokio::test]
+    async fn caps_clients() {
+        let limit = RateLimit::new(
+            RateLimitConfig::new(1, Duration::from_secs(60))
+                .max_clients(2)
+                .key(|req| Client::ApiKey(req.header("X-Api-Key").unwrap_or_default().into())),
+        );
+        let cli = TestClient::new(get(hello).with(limit));
+        let send = |key| cli.get("/").header("X-Api-Key", key).send();
+
+        send("a").await.assert_status_is_ok();
+        send("b").await.assert_status_is_ok();
+        // Everyone after the first two shares a bucket
+        send("c").await.assert_status_is_ok();
+        let resp = send("d").await;
+        resp.assert_status(StatusCode::TOO_MANY_REQUESTS);
+    }
+
+    #[test]
+    fn forwarded_for() {
+        let ip = |address: &str| address.parse::<IpAddr>().ok();
+        let trust = Trust::default().proxies([ip("10.0.0.1").unwrap(), ip("10.0.0.2").unwrap()]);
+        let address = |peer, forwarded| trust.forwarded_for(ip(peer), forwarded);
+
+        assert_eq!(address("203.0.113.9", "198.51.100.1"), ip("203.0.113.9"));
+        assert_eq!(
+            address("10.0.0.1", "198.51.100.1, 203.0.113.7"),
+            ip("203.0.113.7")
+        );
+        assert_eq!(
+            address("10.0.0.1", "198.51.100.1,203.0.113.7, 10.0.0.2"),
+            ip("203.0.113.7")
+        );
+        assert_eq!(address("10.0.0.1", "10.0.0.2, unknown"), ip("10.0.0.1"));
+        assert_eq!(address("10.0.0.1", ""), ip("10.0.0.1"));
+        assert_eq!(address("", "198.51.100.1"), None);
+    }
+
+    #[tokio::test]
+    async fn falls_back_per_instance() {
+        let pool = PgPoolOptions::new()
+            .acquire_timeout(Duration::from_millis(100))
+            .connect_lazy("postgres://127.0.0.1:1/milk")
+            .unwrap();
+        let limit = RateLimit::new(
+            RateLimitConfig::new(1, Duration::from_secs(60))
+                .key(|_| Client::Unknown)
+                .postgres(pool, "fallback"),
+        );
+        let cli = TestClient::new(get(hello).with(limit.clone()));
+
+        cli.get("/").send().await.assert_status_is_ok();
+        let resp = cli.get("/").send().await;
+        resp.assert_status(StatusCode::TOO_MANY_REQUESTS);
+        let shared = limit.shared.as_ref().unwrap();
+        assert!(shared.unreachable.load(Ordering::Relaxed));
+    }
+
+    // Two instances drawing from one bucket, needs `RATE_LIMIT_DATABASE_URL` to run
+    #[tokio::test]
+    async fn shares_buckets_through_postgres() {
+        let Ok(url) = env::var("RATE_LIMIT_DATABASE_URL") else {
+            return;
+        };
+        let pool = PgPool::connect(&url).await.unwrap();
+        setup_table(&pool).await;
+        let instance = || {
+            RateLimit::new(
+                RateLimitConfig::new(3, Duration::from_secs(60))
+                    .key(|_| Client::Unknown)
+                    .postgres(pool.clone(), "shared-test"),
+            )
+        };
+        let (one, other) = (instance(), instance());
+        one.refill(None).await.unwrap();
+        let one = TestClient::new(get(hello).with(one));
+        let other = TestClient::new(get(hello).with(other));
+
+        one.get("/").send().await.assert_status_is_ok();
+        let resp = other.get("/").send().await;
+        resp.assert_status_is_ok();
+        resp.assert_header("RateLimit-Remaining", "1");
+        one.get("/").send().await.assert_status_is_ok();
+        let resp = other.get("/").send().await;
+        resp.assert_status(StatusCode::TOO_MANY_REQUESTS);
+        resp.assert_header("Retry-After", "60");
+    }
+
+    #[test]
+    #[should_panic(expected = "interval_ms has to be at least 1")]
+    fn rejects_short_intervals() {
+        RateLimit::new(RateLimitConfig::new(5, Duration::from_micros(500)));
+    }
+
+    #[test]
+    #[should_panic(expected = "max and refill have to be at least 1")]
+    fn rejects_zero_refill() {
+        RateLimit::new(RateLimitConfig::new(5, Duration::from_secs(1)).refill(0));
+    }
+
+    #[test]
+    fn refilled() {
+        let settings = BucketSettings {
+            initial: 5,
+            max: 5,
+            interval_ms: 1000,
+            refill: 2,
+        };
+        let refilled = |tokens, millis| settings.refilled(tokens, Duration::from_millis(millis));
+
+        assert_eq!(refilled(0, 999), (0, Duration::from_millis(999)));
+        assert_eq!(refilled(0, 2500), (4, Duration::from_millis(500)));
+        assert_eq!(refilled(1, 2000), (5, Duration::ZERO));
+        assert_eq!(refilled(0, u64::MAX), (5, Duration::from_millis(615)));
+    }
+}
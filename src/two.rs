@@ -1,91 +1,1569 @@
 use std::{
-    net::{Ipv4Addr, Ipv6Addr},
-    ops::BitXor,
+    collections::HashMap,
+    error::Error,
+    fmt,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
 };
 
-use poem::{get, handler, web::Query, Response, Route};
-use serde::Deserialize;
+use poem::{
+    error::ResponseError,
+    get, handler,
+    http::StatusCode,
+    post,
+    web::{headers::ContentType, Accept, Json, Query, TypedHeader},
+    FromRequest, IntoResponse, Request, RequestBody, Response, Result, Route,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use shuttlings_cch24::cipher::{Cipher, CipherKind, Mac};
 
-#[derive(Debug, Deserialize)]
-struct Destination {
-    from: String,
-    key: String,
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum Reason {
+    Empty,
+    WrongFamily,
+    BadOctet,
+    BadSegment,
+    BadPrefix,
+    MultipleBlocks,
+    UnknownCipher,
 }
 
-#[handler]
-fn dest(Query(Destination { from, key: ip }): Query<Destination>) -> Response {
-    let from_octets = from.parse::<Ipv4Addr>().unwrap().octets();
-    let key_octets = ip.parse::<Ipv4Addr>().unwrap().octets();
+impl Reason {
+    const ALL: [Self; 7] = [
+        Self::Empty,
+        Self::WrongFamily,
+        Self::BadOctet,
+        Self::BadSegment,
+        Self::BadPrefix,
+        Self::MultipleBlocks,
+        Self::UnknownCipher,
+    ];
+}
+
+#[derive(Debug, Serialize)]
+struct AddrError {
+    parameter: &'static str,
+    value: String,
+    reason: Reason,
+}
+
+impl fmt::Display for AddrError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let Self {
+            parameter, value, ..
+        } = self;
+        match self.reason {
+            Reason::Empty => write!(f, "`{parameter}` is empty"),
+            Reason::WrongFamily => write!(f, "`{parameter}` is of the wrong family: {value}"),
+            Reason::BadOctet => write!(f, "`{parameter}` has a bad octet: {value}"),
+            Reason::BadSegment => write!(f, "`{parameter}` has a bad segment: {value}"),
+            Reason::BadPrefix => write!(f, "`{parameter}` has a bad prefix length: {value}"),
+            Reason::UnknownCipher => write!(f, "`{parameter}` is not a known cipher: {value}"),
+            Reason::MultipleBlocks => {
+                write!(
+                    f,
+                    "`{parameter}` is a block, but only one block is supported"
+                )
+            }
+        }
+    }
+}
+
+impl Error for AddrError {}
+
+impl ResponseError for AddrError {
+    fn status(&self) -> StatusCode {
+        StatusCode::BAD_REQUEST
+    }
+
+    fn as_response(&self) -> Response {
+        Response::builder()
+            .status(self.status())
+            .content_type("application/json")
+            .body(serde_json::to_string(self).expect("Should serialize"))
+    }
+}
+
+fn parse_v4(parameter: &'static str, value: &str) -> Result<Ipv4Addr, AddrError> {
+    value.parse().map_err(|_| AddrError {
+        parameter,
+        value: value.to_owned(),
+        reason: if value.is_empty() {
+            Reason::Empty
+        } else if value.parse::<Ipv6Addr>().is_ok() {
+            Reason::WrongFamily
+        } else {
+            Reason::BadOctet
+        },
+    })
+}
+
+fn parse_ip(parameter: &'static str, value: &str) -> Result<IpAddr, AddrError> {
+    value.parse().map_err(|_| AddrError {
+        parameter,
+        value: value.to_owned(),
+        reason: if value.is_empty() {
+            Reason::Empty
+        } else if value.contains(':') {
+            Reason::BadSegment
+        } else {
+            Reason::BadOctet
+        },
+    })
+}
+
+fn parse_v6(parameter: &'static str, value: &str) -> Result<Ipv6Addr, AddrError> {
+    value.parse().map_err(|_| AddrError {
+        parameter,
+        value: value.to_owned(),
+        reason: if value.is_empty() {
+            Reason::Empty
+        } else if value.parse::<Ipv4Addr>().is_ok() {
+            Reason::WrongFamily
+        } else {
+            Reason::BadSegment
+        },
+    })
+}
+
+enum V4Input {
+    Address(Ipv4Addr),
+    Block(Cidr),
+}
+
+#[derive(Clone, Copy)]
+struct Cidr {
+    network: Ipv4Addr,
+    prefix: u8,
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.network, self.prefix)
+    }
+}
+
+fn parse_v4_input(parameter: &'static str, value: &str) -> Result<V4Input, AddrError> {
+    let Some((address, prefix)) = value.split_once('/') else {
+        return parse_v4(parameter, value).map(V4Input::Address);
+    };
+    let address = parse_v4(parameter, address)?;
+    let prefix = match prefix.parse::<u8>() {
+        Ok(prefix) if prefix <= 32 => prefix,
+        _ => {
+            return Err(AddrError {
+                parameter,
+                value: value.to_owned(),
+                reason: Reason::BadPrefix,
+            })
+        }
+    };
+    // Host bits are dropped, `10.0.0.1/24` is read as `10.0.0.0/24`
+    let mask = (u64::from(u32::MAX) << (32 - prefix)) as u32;
+
+    Ok(V4Input::Block(Cidr {
+        network: Ipv4Addr::from(u32::from(address) & mask),
+        prefix,
+    }))
+}
+
+#[derive(Debug, Serialize)]
+struct AddrRange {
+    start: Ipv4Addr,
+    end: Ipv4Addr,
+}
+
+#[derive(Debug, Serialize)]
+struct BlockSummary {
+    // Only set when the result is itself a single CIDR block
+    network: Option<String>,
+    count: u64,
+    ranges: Vec<AddrRange>,
+}
+
+// Ciphers work on each octet independently, so octets fixed by the prefix stay fixed and fully
+// free octets still cover all 256 values. Only the octet the prefix ends in can be scattered, and
+// every run of values it lands on is one range. Adding the key wraps around at most once, so the
+// default cipher gives one or two ranges.
+fn map_block(
+    Cidr { network, prefix }: Cidr,
+    transform: impl Fn(Ipv4Addr) -> Ipv4Addr,
+) -> BlockSummary {
+    let octet = prefix as usize / 8;
+    if octet == 4 {
+        let address = transform(network);
+        return BlockSummary {
+            network: Some(format!("{address}/32")),
+            count: 1,
+            ranges: vec![AddrRange {
+                start: address,
+                end: address,
+            }],
+        };
+    }
+
+    let free_bits = 8 - prefix % 8;
+    let shift = 8 * (3 - octet as u32);
+    let mut hit = [false; 256];
+    for value in 0..1_u32 << free_bits {
+        let address = transform(Ipv4Addr::from(u32::from(network) | value << shift));
+        hit[address.octets()[octet] as usize] = true;
+    }
+
+    let fixed = u32::from(transform(network)) & (u64::MAX << (shift + 8)) as u32;
+    let host = (1_u32 << shift) - 1;
+    let mut ranges = vec![];
+    let mut value = 0;
+    while value < 256 {
+        if !hit[value] {
+            value += 1;
+            continue;
+        }
+        let low = value;
+        while value < 256 && hit[value] {
+            value += 1;
+        }
+        ranges.push(AddrRange {
+            start: Ipv4Addr::from(fixed | (low as u32) << shift),
+            end: Ipv4Addr::from(fixed | ((value - 1) as u32) << shift | host),
+        });
+    }
+
+    let count = ranges
+        .iter()
+        .map(|AddrRange { start, end }| u64::from(u32::from(*end) - u32::from(*start)) + 1)
+        .sum::<u64>();
+    let network = match ranges.as_slice() {
+        [AddrRange { start, .. }]
+            if count.is_power_of_two() && u64::from(u32::from(*start)) % count == 0 =>
+        {
+            Some(format!("{start}/{}", 32 - count.trailing_zeros()))
+        }
+        _ => None,
+    };
+
+    BlockSummary {
+        network,
+        count,
+        ranges,
+    }
+}
+
+fn multiple_blocks(parameter: &'static str, value: String) -> AddrError {
+    AddrError {
+        parameter,
+        value,
+        reason: Reason::MultipleBlocks,
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct AddressForms {
+    address: IpAddr,
+    family: Family,
+    // Kept as a string, v6 addresses don't fit in the numbers most JSON parsers use
+    integer: String,
+    reverse_dns: String,
+}
+
+impl From<IpAddr> for AddressForms {
+    fn from(address: IpAddr) -> Self {
+        let (family, integer, reverse_dns) = match address {
+            IpAddr::V4(v4) => {
+                let [a, b, c, d] = v4.octets();
+                (
+                    Family::V4,
+                    u32::from(v4).to_string(),
+                    format!("{d}.{c}.{b}.{a}.in-addr.arpa"),
+                )
+            }
+            IpAddr::V6(v6) => (
+                Family::V6,
+                u128::from(v6).to_string(),
+                v6.octets()
+                    .iter()
+                    .rev()
+                    .map(|byte| format!("{:x}.{:x}.", byte & 0xf, byte >> 4))
+                    .collect::<String>()
+                    + "ip6.arpa",
+            ),
+        };
 
-    Ipv4Addr::from(
-        from_octets
+        Self {
+            address,
+            family,
+            integer,
+            reverse_dns,
+        }
+    }
+}
+
+// Picks the first representation the client accepts, plain text when nothing is asked for
+fn represent(address: IpAddr, Accept(accepted): &Accept) -> Response {
+    if accepted.is_empty() {
+        return address.to_string().into_response();
+    }
+
+    for mime in accepted {
+        match mime.essence_str() {
+            "text/plain" | "text/*" | "*/*" => return address.to_string().into_response(),
+            "application/json" => return Json(AddressForms::from(address)).into_response(),
+            "application/octet-stream" => {
+                let bytes = match address {
+                    IpAddr::V4(v4) => v4.octets().to_vec(),
+                    IpAddr::V6(v6) => v6.octets().to_vec(),
+                };
+                return bytes
+                    .with_content_type("application/octet-stream")
+                    .into_response();
+            }
+            _ => {}
+        }
+    }
+
+    StatusCode::NOT_ACCEPTABLE.into()
+}
+
+// Block summaries only come as JSON, so clients that can't take it get a 406
+fn summarize(summary: BlockSummary, Accept(accepted): &Accept) -> Response {
+    let json = accepted.is_empty()
+        || accepted
             .iter()
-            .zip(key_octets.iter())
-            .fold(0_u32, |x, (a, b)| (x << 8) + b.overflowing_add(*a).0 as u32),
-    )
-    .to_string()
-    .into()
+            .any(|mime| matches!(mime.essence_str(), "application/json" | "*/*"));
+    if !json {
+        return StatusCode::NOT_ACCEPTABLE.into();
+    }
+
+    Json(summary).into_response()
+}
+
+// Accepts `aa:bb:cc:dd:ee:ff` and `aa-bb-cc-dd-ee-ff`
+fn parse_mac(parameter: &'static str, value: &str) -> Result<Mac, AddrError> {
+    let error = |reason| AddrError {
+        parameter,
+        value: value.to_owned(),
+        reason,
+    };
+    if value.is_empty() {
+        return Err(error(Reason::Empty));
+    }
+
+    let mut octets = [0; 6];
+    let mut parts = value.split([':', '-']);
+    for octet in octets.iter_mut() {
+        *octet = parts
+            .next()
+            .filter(|part| part.len() == 2)
+            .and_then(|part| u8::from_str_radix(part, 16).ok())
+            .ok_or_else(|| error(Reason::BadOctet))?;
+    }
+    if parts.next().is_some() {
+        return Err(error(Reason::BadOctet));
+    }
+
+    Ok(Mac(octets))
+}
+
+// An address type that can be read from a query parameter and described in the OpenAPI document
+trait AddrParam: Sized + Send {
+    const DESCRIPTION: &'static str;
+    // Examples for `from` and for the other address of the pair
+    const EXAMPLES: [&'static str; 2];
+
+    fn parse(parameter: &'static str, value: &str) -> Result<Self, AddrError>;
+}
+
+impl AddrParam for Ipv4Addr {
+    const DESCRIPTION: &'static str = "A v4 address";
+    const EXAMPLES: [&'static str; 2] = ["10.0.0.0", "1.2.3.255"];
+
+    fn parse(parameter: &'static str, value: &str) -> Result<Self, AddrError> {
+        parse_v4(parameter, value)
+    }
+}
+
+impl AddrParam for V4Input {
+    const DESCRIPTION: &'static str = "A v4 address or CIDR block, at most one block per request";
+    const EXAMPLES: [&'static str; 2] = ["10.0.0.0/24", "1.2.3.255"];
+
+    fn parse(parameter: &'static str, value: &str) -> Result<Self, AddrError> {
+        parse_v4_input(parameter, value)
+    }
+}
+
+impl AddrParam for Ipv6Addr {
+    const DESCRIPTION: &'static str = "A v6 address";
+    const EXAMPLES: [&'static str; 2] = ["fe80::1", "5:6:7::3333"];
+
+    fn parse(parameter: &'static str, value: &str) -> Result<Self, AddrError> {
+        parse_v6(parameter, value)
+    }
+}
+
+impl AddrParam for IpAddr {
+    const DESCRIPTION: &'static str =
+        "A v4 or v6 address, including `::ffff:a.b.c.d` and `::a.b.c.d`";
+    const EXAMPLES: [&'static str; 2] = ["::ffff:10.0.0.0", "1.2.3.255"];
+
+    fn parse(parameter: &'static str, value: &str) -> Result<Self, AddrError> {
+        parse_ip(parameter, value)
+    }
+}
+
+impl AddrParam for Mac {
+    const DESCRIPTION: &'static str = "A MAC address, `:` or `-` separated";
+    const EXAMPLES: [&'static str; 2] = ["00:11:22:33:44:ff", "01:01:01:01:01:01"];
+
+    fn parse(parameter: &'static str, value: &str) -> Result<Self, AddrError> {
+        parse_mac(parameter, value)
+    }
+}
+
+struct Params(HashMap<String, String>);
+
+impl Params {
+    async fn extract(req: &Request) -> Result<Self> {
+        let Query(params) = Query::from_request_without_body(req).await?;
+        Ok(Self(params))
+    }
+
+    // Missing parameters are read as empty strings so they are reported as `empty`
+    fn addr<A: AddrParam>(&self, parameter: &'static str) -> Result<A, AddrError> {
+        A::parse(parameter, self.0.get(parameter).map_or("", String::as_str))
+    }
+
+    fn optional_addr<A: AddrParam>(&self, parameter: &'static str) -> Result<Option<A>, AddrError> {
+        self.0
+            .get(parameter)
+            .map(|value| A::parse(parameter, value))
+            .transpose()
+    }
+
+    fn cipher(&self) -> Result<Option<CipherKind>, AddrError> {
+        self.0
+            .get("cipher")
+            .map(|value| {
+                value.parse().map_err(|_| AddrError {
+                    parameter: "cipher",
+                    value: value.to_owned(),
+                    reason: Reason::UnknownCipher,
+                })
+            })
+            .transpose()
+    }
+}
+
+fn parameter(name: &str, description: &str, example: &str, required: bool) -> Value {
+    json!({
+        "name": name,
+        "in": "query",
+        "required": required,
+        "description": description,
+        "schema": {"type": "string"},
+        "example": example,
+    })
+}
+
+fn cipher_parameter() -> Value {
+    json!({
+        "name": "cipher",
+        "in": "query",
+        "required": false,
+        "description": "Defaults to `add` for v4 and MAC addresses and to `xor` for v6 addresses",
+        "schema": {"type": "string", "enum": CipherKind::ALL},
+        "example": "add",
+    })
+}
+
+struct Destination<A> {
+    from: A,
+    key: A,
+    cipher: Option<CipherKind>,
+}
+
+impl<A: AddrParam> Destination<A> {
+    fn parameters() -> Value {
+        json!([
+            parameter("from", A::DESCRIPTION, A::EXAMPLES[0], true),
+            parameter("key", A::DESCRIPTION, A::EXAMPLES[1], true),
+            cipher_parameter(),
+        ])
+    }
+}
+
+impl<'a, A: AddrParam> FromRequest<'a> for Destination<A> {
+    async fn from_request(req: &'a Request, _body: &mut RequestBody) -> Result<Self> {
+        let params = Params::extract(req).await?;
+        Ok(Self {
+            from: params.addr("from")?,
+            key: params.addr("key")?,
+            cipher: params.cipher()?,
+        })
+    }
+}
+
+struct Key<A> {
+    from: A,
+    to: A,
+    cipher: Option<CipherKind>,
+}
+
+impl<A: AddrParam> Key<A> {
+    fn parameters() -> Value {
+        json!([
+            parameter("from", A::DESCRIPTION, A::EXAMPLES[0], true),
+            parameter("to", A::DESCRIPTION, A::EXAMPLES[1], true),
+            cipher_parameter(),
+        ])
+    }
+}
+
+impl<'a, A: AddrParam> FromRequest<'a> for Key<A> {
+    async fn from_request(req: &'a Request, _body: &mut RequestBody) -> Result<Self> {
+        let params = Params::extract(req).await?;
+        Ok(Self {
+            from: params.addr("from")?,
+            to: params.addr("to")?,
+            cipher: params.cipher()?,
+        })
+    }
 }
 
 #[handler]
-fn dest_v6(Query(Destination { from, key: ip }): Query<Destination>) -> Response {
-    let from_segments = from.parse::<Ipv6Addr>().unwrap().segments();
-    let key_segments = ip.parse::<Ipv6Addr>().unwrap().segments();
+fn dest(
+    Destination {
+        from,
+        key: ip,
+        cipher,
+    }: Destination<V4Input>,
+    accept: Accept,
+) -> Response {
+    let cipher = cipher.unwrap_or(CipherKind::Add);
+    let summary = match (from, ip) {
+        (V4Input::Address(from), V4Input::Address(ip)) => {
+            return represent(cipher.encrypt_v4(from, ip).into(), &accept)
+        }
+        (V4Input::Block(from), V4Input::Address(ip)) => {
+            map_block(from, |from| cipher.encrypt_v4(from, ip))
+        }
+        (V4Input::Address(from), V4Input::Block(ip)) => {
+            map_block(ip, |ip| cipher.encrypt_v4(from, ip))
+        }
+        (V4Input::Block(_), V4Input::Block(ip)) => {
+            return multiple_blocks("key", ip.to_string()).as_response()
+        }
+    };
 
-    Ipv6Addr::from(
-        from_segments
-            .iter()
-            .zip(key_segments.iter())
-            .fold(0_u128, |x, (a, b)| (x << 16) + b.bitxor(*a) as u128),
-    )
-    .to_string()
-    .into()
+    summarize(summary, &accept)
 }
 
-#[derive(Debug, Deserialize)]
-struct Key {
-    from: String,
-    to: String,
+#[handler]
+fn dest_v6(
+    Destination {
+        from,
+        key: ip,
+        cipher,
+    }: Destination<Ipv6Addr>,
+    accept: Accept,
+) -> Response {
+    let cipher = cipher.unwrap_or(CipherKind::Xor);
+
+    represent(cipher.encrypt_v6(from, ip).into(), &accept)
 }
 
 #[handler]
-fn key(Query(Key { from, to }): Query<Key>) -> Response {
-    let from_octets = from.parse::<Ipv4Addr>().unwrap().octets();
-    let to_octets = to.parse::<Ipv4Addr>().unwrap().octets();
+fn key(Key { from, to, cipher }: Key<V4Input>, accept: Accept) -> Response {
+    let cipher = cipher.unwrap_or(CipherKind::Add);
+    let summary = match (from, to) {
+        (V4Input::Address(from), V4Input::Address(to)) => {
+            return represent(cipher.derive_key_v4(from, to).into(), &accept)
+        }
+        (V4Input::Block(from), V4Input::Address(to)) => {
+            map_block(from, |from| cipher.derive_key_v4(from, to))
+        }
+        (V4Input::Address(from), V4Input::Block(to)) => {
+            map_block(to, |to| cipher.derive_key_v4(from, to))
+        }
+        (V4Input::Block(_), V4Input::Block(to)) => {
+            return multiple_blocks("to", to.to_string()).as_response()
+        }
+    };
 
-    Ipv4Addr::from(
-        from_octets
-            .iter()
-            .zip(to_octets.iter())
-            .fold(0_u32, |x, (a, b)| (x << 8) + b.overflowing_sub(*a).0 as u32),
-    )
-    .to_string()
-    .into()
+    summarize(summary, &accept)
 }
 
 #[handler]
-fn key_v6(Query(Key { from, to }): Query<Key>) -> Response {
-    let from_segments = from.parse::<Ipv6Addr>().unwrap().segments();
-    let to_segments = to.parse::<Ipv6Addr>().unwrap().segments();
+fn key_v6(Key { from, to, cipher }: Key<Ipv6Addr>, accept: Accept) -> Response {
+    let cipher = cipher.unwrap_or(CipherKind::Xor);
 
-    Ipv6Addr::from(
-        from_segments
-            .iter()
-            .zip(to_segments.iter())
-            .fold(0_u128, |x, (a, b)| (x << 16) + b.bitxor(*a) as u128),
-    )
-    .to_string()
-    .into()
+    represent(cipher.derive_key_v6(from, to).into(), &accept)
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Form {
+    Plain,
+    // `::ffff:a.b.c.d`
+    Mapped,
+    // `::a.b.c.d`, excluding `::` and `::1` which are read as plain v6
+    Compatible,
+}
+
+impl Form {
+    fn of(address: IpAddr) -> Option<(Ipv4Addr, Self)> {
+        match address {
+            IpAddr::V4(address) => Some((address, Self::Plain)),
+            IpAddr::V6(address) => match address.to_ipv4_mapped() {
+                Some(embedded) => Some((embedded, Self::Mapped)),
+                None if address.segments()[..6] == [0; 6]
+                    && !address.is_unspecified()
+                    && !address.is_loopback() =>
+                {
+                    address
+                        .to_ipv4()
+                        .map(|embedded| (embedded, Self::Compatible))
+                }
+                None => None,
+            },
+        }
+    }
+
+    fn wrap(self, address: Ipv4Addr) -> IpAddr {
+        match self {
+            Self::Plain => address.into(),
+            Self::Mapped => address.to_ipv6_mapped().into(),
+            Self::Compatible => address.to_ipv6_compatible().into(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum Rule {
+    // Both addresses are v4, octets are added or subtracted
+    V4,
+    // Both addresses are v6 and at least one doesn't embed a v4 address, segments are XORed
+    V6,
+    // Both addresses are v4 or embed one, the v4 cipher runs on the embedded addresses and the
+    // result is written back in the form of `from`, or of the other address if `from` is plain
+    EmbeddedV4,
+    // A plain v4 address is paired with a v6 one, it is mapped to `::ffff:a.b.c.d` and segments
+    // are XORed
+    MappedV4,
+}
+
+impl Rule {
+    const ALL: [Self; 4] = [Self::V4, Self::V6, Self::EmbeddedV4, Self::MappedV4];
+}
+
+#[derive(Debug, Serialize)]
+struct Translated {
+    address: IpAddr,
+    rule: Rule,
+}
+
+fn to_v6(address: IpAddr) -> Ipv6Addr {
+    match address {
+        IpAddr::V4(address) => address.to_ipv6_mapped(),
+        IpAddr::V6(address) => address,
+    }
+}
+
+fn translate(
+    from: IpAddr,
+    ip: IpAddr,
+    v4: impl Fn(Ipv4Addr, Ipv4Addr) -> Ipv4Addr,
+    v6: impl Fn(Ipv6Addr, Ipv6Addr) -> Ipv6Addr,
+) -> Translated {
+    match (Form::of(from), Form::of(ip)) {
+        (Some((from, Form::Plain)), Some((ip, Form::Plain))) => Translated {
+            address: v4(from, ip).into(),
+            rule: Rule::V4,
+        },
+        (Some((from, from_form)), Some((ip, ip_form))) => Translated {
+            address: match from_form {
+                Form::Plain => ip_form,
+                _ => from_form,
+            }
+            .wrap(v4(from, ip)),
+            rule: Rule::EmbeddedV4,
+        },
+        _ => Translated {
+            address: v6(to_v6(from), to_v6(ip)).into(),
+            rule: if from.is_ipv4() || ip.is_ipv4() {
+                Rule::MappedV4
+            } else {
+                Rule::V6
+            },
+        },
+    }
+}
+
+#[handler]
+fn dest_auto(
+    Destination {
+        from,
+        key: ip,
+        cipher,
+    }: Destination<IpAddr>,
+) -> Json<Translated> {
+    let v4 = cipher.unwrap_or(CipherKind::Add);
+    let v6 = cipher.unwrap_or(CipherKind::Xor);
+
+    Json(translate(
+        from,
+        ip,
+        |from, ip| v4.encrypt_v4(from, ip),
+        |from, ip| v6.encrypt_v6(from, ip),
+    ))
+}
+
+#[handler]
+fn key_auto(Key { from, to, cipher }: Key<IpAddr>) -> Json<Translated> {
+    let v4 = cipher.unwrap_or(CipherKind::Add);
+    let v6 = cipher.unwrap_or(CipherKind::Xor);
+
+    Json(translate(
+        from,
+        to,
+        |from, to| v4.derive_key_v4(from, to),
+        |from, to| v6.derive_key_v6(from, to),
+    ))
+}
+
+#[handler]
+fn dest_mac(
+    Destination {
+        from,
+        key: ip,
+        cipher,
+    }: Destination<Mac>,
+) -> Response {
+    let cipher = cipher.unwrap_or(CipherKind::Add);
+
+    cipher.encrypt_mac(from, ip).to_string().into_response()
+}
+
+#[handler]
+fn key_mac(Key { from, to, cipher }: Key<Mac>) -> Response {
+    let cipher = cipher.unwrap_or(CipherKind::Add);
+
+    cipher.derive_key_mac(from, to).to_string().into_response()
+}
+
+struct Eui64 {
+    mac: Mac,
+    key: Ipv6Addr,
+    // Only the upper 64 bits are used, defaults to the link-local `fe80::/64`
+    prefix: Option<Ipv6Addr>,
+    cipher: Option<CipherKind>,
+}
+
+impl Eui64 {
+    fn parameters() -> Value {
+        json!([
+            parameter("mac", Mac::DESCRIPTION, Mac::EXAMPLES[0], true),
+            parameter("key", Ipv6Addr::DESCRIPTION, Ipv6Addr::EXAMPLES[1], true),
+            parameter(
+                "prefix",
+                "A v6 address whose upper 64 bits are used as the prefix, defaults to `fe80::`",
+                "2001:db8::",
+                false,
+            ),
+            cipher_parameter(),
+        ])
+    }
+}
+
+impl<'a> FromRequest<'a> for Eui64 {
+    async fn from_request(req: &'a Request, _body: &mut RequestBody) -> Result<Self> {
+        let params = Params::extract(req).await?;
+        Ok(Self {
+            mac: params.addr("mac")?,
+            key: params.addr("key")?,
+            prefix: params.optional_addr("prefix")?,
+            cipher: params.cipher()?,
+        })
+    }
+}
+
+#[handler]
+fn eui64(
+    Eui64 {
+        mac,
+        key: ip,
+        prefix,
+        cipher,
+    }: Eui64,
+    accept: Accept,
+) -> Response {
+    let cipher = cipher.unwrap_or(CipherKind::Xor);
+    let prefix = prefix.unwrap_or(Ipv6Addr::new(0xfe80, 0, 0, 0, 0, 0, 0, 0));
+    let from =
+        Ipv6Addr::from(u128::from(prefix) & !u128::from(u64::MAX) | u128::from(mac.interface_id()));
+
+    represent(cipher.encrypt_v6(from, ip).into(), &accept)
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum Family {
+    V4,
+    V6,
+}
+
+impl Family {
+    const ALL: [Self; 2] = [Self::V4, Self::V6];
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "op", rename_all = "lowercase")]
+enum Operation {
+    Dest {
+        family: Family,
+        #[serde(default)]
+        from: String,
+        #[serde(default, rename = "key")]
+        ip: String,
+        cipher: Option<CipherKind>,
+    },
+    Key {
+        family: Family,
+        #[serde(default)]
+        from: String,
+        #[serde(default)]
+        to: String,
+        cipher: Option<CipherKind>,
+    },
+}
+
+impl Operation {
+    fn run(self) -> Result<IpAddr, AddrError> {
+        Ok(match self {
+            Self::Dest {
+                family: Family::V4,
+                from,
+                ip,
+                cipher,
+            } => cipher
+                .unwrap_or(CipherKind::Add)
+                .encrypt_v4(parse_v4("from", &from)?, parse_v4("key", &ip)?)
+                .into(),
+            Self::Dest {
+                family: Family::V6,
+                from,
+                ip,
+                cipher,
+            } => cipher
+                .unwrap_or(CipherKind::Xor)
+                .encrypt_v6(parse_v6("from", &from)?, parse_v6("key", &ip)?)
+                .into(),
+            Self::Key {
+                family: Family::V4,
+                from,
+                to,
+                cipher,
+            } => cipher
+                .unwrap_or(CipherKind::Add)
+                .derive_key_v4(parse_v4("from", &from)?, parse_v4("to", &to)?)
+                .into(),
+            Self::Key {
+                family: Family::V6,
+                from,
+                to,
+                cipher,
+            } => cipher
+                .unwrap_or(CipherKind::Xor)
+                .derive_key_v6(parse_v6("from", &from)?, parse_v6("to", &to)?)
+                .into(),
+        })
+    }
+}
+
+#[derive(Debug, Serialize)]
+#[serde(untagged)]
+enum Outcome {
+    Address { address: IpAddr },
+    Failed { error: AddrError },
+    Invalid { error: String },
+}
+
+impl From<serde_json::Result<Operation>> for Outcome {
+    fn from(operation: serde_json::Result<Operation>) -> Self {
+        match operation.map(Operation::run) {
+            Ok(Ok(address)) => Self::Address { address },
+            Ok(Err(error)) => Self::Failed { error },
+            Err(e) => Self::Invalid {
+                error: e.to_string(),
+            },
+        }
+    }
+}
+
+#[handler]
+async fn batch(data: String, TypedHeader(content_type): TypedHeader<ContentType>) -> Response {
+    // Parameters such as `charset` are ignored, the body has already been read as UTF-8
+    let content_type = content_type.to_string().to_lowercase();
+    let essence = content_type.split(';').next().unwrap_or_default().trim();
+    match essence {
+        "application/json" => {
+            let Ok(operations) = serde_json::from_str::<Vec<Value>>(&data) else {
+                return (StatusCode::BAD_REQUEST, "Expected a JSON array").into_response();
+            };
+            let outcomes = operations
+                .into_iter()
+                .map(|operation| Outcome::from(serde_json::from_value(operation)))
+                .collect::<Vec<_>>();
+
+            Json(outcomes).into_response()
+        }
+        // Each line is handled on its own, so a malformed line only fails that entry
+        "application/x-ndjson" => data
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+            .map(|line| {
+                let outcome = Outcome::from(serde_json::from_str(line));
+                serde_json::to_string(&outcome).expect("Should serialize") + "\n"
+            })
+            .collect::<String>()
+            .with_content_type("application/x-ndjson")
+            .into_response(),
+        _ => StatusCode::UNSUPPORTED_MEDIA_TYPE.into(),
+    }
+}
+
+fn operation(method: &str, summary: &str, parameters: Value, ok: Value) -> Value {
+    json!({
+        method: {
+            "summary": summary,
+            "parameters": parameters,
+            "responses": {
+                "200": ok,
+                "400": {
+                    "description": "A parameter is missing or malformed",
+                    "content": {
+                        "application/json": {"schema": {"$ref": "#/components/schemas/AddrError"}},
+                    },
+                },
+            },
+        },
+    })
+}
+
+// For routes that negotiate their representation from `Accept`
+fn negotiated(mut operation: Value) -> Value {
+    operation["get"]["responses"]["406"] =
+        json!({"description": "None of the accepted types can represent the result"});
+    operation
+}
+
+fn address_response() -> Value {
+    json!({
+        "description": "The resulting address, in the representation picked from `Accept`",
+        "content": {
+            "text/plain": {"schema": {"type": "string"}, "example": "11.2.3.255"},
+            "application/json": {"schema": {"$ref": "#/components/schemas/AddressForms"}},
+            "application/octet-stream": {"schema": {"type": "string", "format": "binary"}},
+        },
+    })
+}
+
+// The schema of the JSON `example` serializes to, with every field it has marked as required.
+// Enum fields only show the example's variant, callers list the others
+fn schema_of(example: impl Serialize) -> Value {
+    fn describe(value: Value) -> Value {
+        match value {
+            Value::Object(fields) => {
+                let required = fields.keys().cloned().collect::<Vec<_>>();
+                let properties = fields
+                    .into_iter()
+                    .map(|(name, field)| (name, describe(field)))
+                    .collect::<serde_json::Map<_, _>>();
+                json!({"type": "object", "required": required, "properties": properties})
+            }
+            Value::Array(items) => {
+                json!({"type": "array", "items": items.into_iter().next().map(describe)})
+            }
+            Value::Number(number) if number.is_f64() => {
+                json!({"type": "number", "example": number})
+            }
+            Value::Number(number) => json!({"type": "integer", "example": number}),
+            Value::String(string) => json!({"type": "string", "example": string}),
+            Value::Bool(bool) => json!({"type": "boolean", "example": bool}),
+            Value::Null => json!({"nullable": true}),
+        }
+    }
+
+    describe(serde_json::to_value(example).expect("Should serialize"))
+}
+
+fn schemas() -> Value {
+    let mut error = schema_of(AddrError {
+        parameter: "from",
+        value: "10.0.0.256".to_owned(),
+        reason: Reason::BadOctet,
+    });
+    error["properties"]["reason"]["enum"] = json!(Reason::ALL);
+    let mut forms = schema_of(AddressForms::from(IpAddr::from([11, 2, 3, 255])));
+    forms["properties"]["family"]["enum"] = json!(Family::ALL);
+    let block = Cidr {
+        network: Ipv4Addr::new(10, 0, 0, 0),
+        prefix: 24,
+    };
+    let mut summary = schema_of(map_block(block, |from| {
+        CipherKind::Add.encrypt_v4(from, Ipv4Addr::new(1, 2, 3, 255))
+    }));
+    summary["properties"]["network"]["nullable"] = json!(true);
+    let mut translated = schema_of(Translated {
+        address: Ipv4Addr::new(11, 2, 3, 255).to_ipv6_mapped().into(),
+        rule: Rule::EmbeddedV4,
+    });
+    translated["properties"]["rule"]["enum"] = json!(Rule::ALL);
+    let mut invalid = schema_of(Outcome::Invalid {
+        error: "unknown variant `shift`, expected `dest` or `key`".to_owned(),
+    });
+    invalid["properties"]["error"] = json!({
+        "oneOf": [{"$ref": "#/components/schemas/AddrError"}, invalid["properties"]["error"]],
+    });
+
+    json!({
+        "AddrError": error,
+        "AddressForms": forms,
+        "BlockSummary": summary,
+        "Translated": translated,
+        // Only read, so it can't be described from a serialized example
+        "Operation": {
+            "type": "object",
+            "required": ["op", "family", "from"],
+            "properties": {
+                "op": {"type": "string", "enum": ["dest", "key"]},
+                "family": {"type": "string", "enum": Family::ALL},
+                "from": {"type": "string"},
+                "key": {"type": "string", "description": "Required for `dest`"},
+                "to": {"type": "string", "description": "Required for `key`"},
+                "cipher": {"type": "string", "enum": CipherKind::ALL},
+            },
+        },
+        "Outcome": {
+            "oneOf": [
+                schema_of(Outcome::Address {
+                    address: IpAddr::from([11, 2, 3, 255]),
+                }),
+                invalid,
+            ],
+        },
+    })
+}
+
+fn document() -> Value {
+    let address = address_response();
+    let block_or_address = json!({
+        "description": "The resulting address, or a summary of the resulting ranges when a block is given. Summaries are only served as JSON",
+        "content": {
+            "text/plain": address["content"]["text/plain"],
+            "application/json": {
+                "schema": {
+                    "oneOf": [
+                        {"$ref": "#/components/schemas/AddressForms"},
+                        {"$ref": "#/components/schemas/BlockSummary"},
+                    ],
+                },
+            },
+            "application/octet-stream": address["content"]["application/octet-stream"],
+        },
+    });
+    let translated = json!({
+        "description": "The resulting address and the rule used for its families",
+        "content": {
+            "application/json": {"schema": {"$ref": "#/components/schemas/Translated"}},
+        },
+    });
+    let mac = json!({
+        "description": "The resulting MAC address",
+        "content": {"text/plain": {"schema": {"type": "string"}, "example": "01:12:23:34:45:00"}},
+    });
+    let mut operations = operation(
+        "post",
+        "Runs a batch of operations in order",
+        json!([]),
+        json!({
+            "description": "One outcome per operation, in order",
+            "content": {
+                "application/json": {
+                    "schema": {"type": "array", "items": {"$ref": "#/components/schemas/Outcome"}},
+                },
+                "application/x-ndjson": {"schema": {"$ref": "#/components/schemas/Outcome"}},
+            },
+        }),
+    );
+    operations["post"]["requestBody"] = json!({
+        "required": true,
+        "content": {
+            "application/json": {
+                "schema": {"type": "array", "items": {"$ref": "#/components/schemas/Operation"}},
+                "example": [{"op": "dest", "family": "v4", "from": "10.0.0.0", "key": "1.2.3.255"}],
+            },
+            "application/x-ndjson": {"schema": {"$ref": "#/components/schemas/Operation"}},
+        },
+    });
+    operations["post"]["responses"]["400"] = json!({
+        "description": "The body isn't a JSON array",
+        "content": {"text/plain": {"schema": {"type": "string"}}},
+    });
+    operations["post"]["responses"]["415"] = json!({"description": "Unsupported content type"});
+
+    json!({
+        "openapi": "3.0.3",
+        "info": {"title": "Day 2 address cipher", "version": env!("CARGO_PKG_VERSION")},
+        "servers": [{"url": "/2"}],
+        "paths": {
+            "/dest": negotiated(operation(
+                "get",
+                "Encrypts a v4 address or block with a key",
+                Destination::<V4Input>::parameters(),
+                block_or_address.clone(),
+            )),
+            "/key": negotiated(operation(
+                "get",
+                "Recovers the key that encrypted a v4 address or block",
+                Key::<V4Input>::parameters(),
+                block_or_address,
+            )),
+            "/v6/dest": negotiated(operation(
+                "get",
+                "Encrypts a v6 address with a key",
+                Destination::<Ipv6Addr>::parameters(),
+                address.clone(),
+            )),
+            "/v6/key": negotiated(operation(
+                "get",
+                "Recovers the key that encrypted a v6 address",
+                Key::<Ipv6Addr>::parameters(),
+                address.clone(),
+            )),
+            "/v6/eui64": negotiated(operation(
+                "get",
+                "Builds a v6 address from a MAC and encrypts it with a key",
+                Eui64::parameters(),
+                address,
+            )),
+            "/auto/dest": operation(
+                "get",
+                "Encrypts an address of either family with a key",
+                Destination::<IpAddr>::parameters(),
+                translated.clone(),
+            ),
+            "/auto/key": operation(
+                "get",
+                "Recovers the key that encrypted an address of either family",
+                Key::<IpAddr>::parameters(),
+                translated,
+            ),
+            "/mac/dest": operation(
+                "get",
+                "Encrypts a MAC address with a key",
+                Destination::<Mac>::parameters(),
+                mac.clone(),
+            ),
+            "/mac/key": operation(
+                "get",
+                "Recovers the key that encrypted a MAC address",
+                Key::<Mac>::parameters(),
+                mac,
+            ),
+            "/batch": operations,
+        },
+        "components": {"schemas": schemas()},
+    })
+}
+
+#[handler]
+fn openapi() -> Json<Value> {
+    Json(document())
 }
 
 pub fn day_two() -> Route {
     Route::new()
         .at("/dest", get(dest))
         .at("/key", get(key))
+        .at("/batch", post(batch))
+        .at("/openapi.json", get(openapi))
         .nest(
             "/v6",
             Route::new()
                 .at("/dest", get(dest_v6))
-                .at("/key", get(key_v6)),
+                .at("/key", get(key_v6))
+                .at("/eui64", get(eui64)),
         )
+        .nest(
+            "/mac",
+            Route::new()
+                .at("/dest", get(dest_mac))
+                .at("/key", get(key_mac)),
+        )
+        .nest(
+            "/auto",
+            Route::new()
+                .at("/dest", get(dest_auto))
+                .at("/key", get(key_auto)),
+        )
+}
+
+#[cfg(test)]
+mod tests {
+    use poem::{http::StatusCode, test::TestClient};
+    use serde_json::json;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn routes() {
+        let cli = TestClient::new(day_two());
+
+        let resp = cli.get("/dest?from=10.0.0.0&key=1.2.3.255").send().await;
+        resp.assert_status_is_ok();
+        resp.assert_text("11.2.3.255").await;
+
+        let resp = cli.get("/key?from=10.0.0.0&to=11.2.3.255").send().await;
+        resp.assert_status_is_ok();
+        resp.assert_text("1.2.3.255").await;
+
+        let resp = cli
+            .get("/v6/dest?from=fe80::1&key=5:6:7::3333")
+            .send()
+            .await;
+        resp.assert_status_is_ok();
+        resp.assert_text("fe85:6:7::3332").await;
+
+        let resp = cli
+            .get("/v6/key?from=aaaa::aaaa&to=5555::5555")
+            .send()
+            .await;
+        resp.assert_status_is_ok();
+        resp.assert_text("ffff::ffff").await;
+    }
+
+    #[tokio::test]
+    async fn mac() {
+        let cli = TestClient::new(day_two());
+
+        let resp = cli
+            .get("/mac/dest?from=00:11:22:33:44:ff&key=01-01-01-01-01-01")
+            .send()
+            .await;
+        resp.assert_status_is_ok();
+        resp.assert_text("01:12:23:34:45:00").await;
+
+        let resp = cli
+            .get("/mac/key?from=00:11:22:33:44:ff&to=01:12:23:34:45:00")
+            .send()
+            .await;
+        resp.assert_status_is_ok();
+        resp.assert_text("01:01:01:01:01:01").await;
+
+        let resp = cli
+            .get("/v6/eui64?mac=00:11:22:33:44:55&key=::1&prefix=2001:db8::")
+            .send()
+            .await;
+        resp.assert_status_is_ok();
+        resp.assert_text("2001:db8::211:22ff:fe33:4454").await;
+    }
+
+    #[tokio::test]
+    async fn negotiation() {
+        let cli = TestClient::new(day_two());
+
+        let resp = cli
+            .get("/dest?from=10.0.0.0&key=1.2.3.255")
+            .header("Accept", "application/json")
+            .send()
+            .await;
+        resp.assert_status_is_ok();
+        resp.assert_json(json!({
+            "address": "11.2.3.255",
+            "family": "v4",
+            "integer": "184681471",
+            "reverse_dns": "255.3.2.11.in-addr.arpa",
+        }))
+        .await;
+
+        let resp = cli
+            .get("/v6/key?from=aaaa::aaaa&to=5555::5555")
+            .header("Accept", "application/octet-stream")
+            .send()
+            .await;
+        resp.assert_status_is_ok();
+        resp.assert_bytes(Ipv6Addr::new(0xffff, 0, 0, 0, 0, 0, 0, 0xffff).octets())
+            .await;
+
+        let resp = cli
+            .get("/v6/key?from=aaaa::aaaa&to=5555::5555")
+            .header("Accept", "application/json")
+            .send()
+            .await;
+        resp.assert_json(json!({
+            "address": "ffff::ffff",
+            "family": "v6",
+            "integer": "340277174624079928635746076935439056895",
+            "reverse_dns": "f.f.f.f.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.f.f.f.f.ip6.arpa",
+        }))
+        .await;
+
+        let resp = cli
+            .get("/dest?from=10.0.0.0&key=1.2.3.255")
+            .header("Accept", "image/png")
+            .send()
+            .await;
+        resp.assert_status(StatusCode::NOT_ACCEPTABLE);
+
+        // Block summaries are only served as JSON
+        for accept in ["text/plain", "application/octet-stream"] {
+            let resp = cli
+                .get("/dest?from=10.0.0.0/24&key=1.2.3.255")
+                .header("Accept", accept)
+                .send()
+                .await;
+            resp.assert_status(StatusCode::NOT_ACCEPTABLE);
+        }
+        let resp = cli
+            .get("/key?from=10.0.0.0&to=11.2.3.0/24")
+            .header("Accept", "text/plain, application/json")
+            .send()
+            .await;
+        resp.assert_status_is_ok();
+        resp.assert_content_type("application/json; charset=utf-8");
+    }
+
+    #[tokio::test]
+    async fn openapi_paths_are_routed() {
+        let cli = TestClient::new(day_two());
+
+        let resp = cli.get("/openapi.json").send().await;
+        resp.assert_status_is_ok();
+        let document = resp.json().await.value().deserialize::<Value>();
+
+        for (path, item) in document["paths"]
+            .as_object()
+            .expect("Paths should be an object")
+        {
+            let resp = if item.get("post").is_some() {
+                cli.post(path).send().await
+            } else {
+                cli.get(path).send().await
+            };
+            assert_ne!(
+                resp.0.status(),
+                StatusCode::NOT_FOUND,
+                "{path} isn't routed"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn auto() {
+        let cli = TestClient::new(day_two());
+
+        for (path, address, rule) in [
+            ("/auto/dest?from=10.0.0.0&key=1.2.3.255", "11.2.3.255", "v4"),
+            (
+                "/auto/dest?from=fe80::1&key=5:6:7::3333",
+                "fe85:6:7::3332",
+                "v6",
+            ),
+            // `::1` is read as plain v6, not as the embedded `0.0.0.1`
+            ("/auto/dest?from=::1&key=::2", "::3", "v6"),
+            (
+                "/auto/dest?from=::ffff:10.0.0.0&key=1.2.3.255",
+                "::ffff:11.2.3.255",
+                "embedded_v4",
+            ),
+            // A plain `from` takes the form of the other address, `::11.2.3.255` here
+            (
+                "/auto/dest?from=10.0.0.0&key=::1.2.3.255",
+                "::b02:3ff",
+                "embedded_v4",
+            ),
+            (
+                "/auto/key?from=::ffff:10.0.0.0&to=::ffff:11.2.3.255",
+                "::ffff:1.2.3.255",
+                "embedded_v4",
+            ),
+            (
+                "/auto/dest?from=10.0.0.0&key=fe80::1",
+                "fe80::ffff:a00:1",
+                "mapped_v4",
+            ),
+            (
+                "/auto/key?from=10.0.0.0&to=fe80::ffff:a00:1",
+                "fe80::1",
+                "mapped_v4",
+            ),
+        ] {
+            let resp = cli.get(path).send().await;
+            resp.assert_status_is_ok();
+            resp.assert_json(json!({"address": address, "rule": rule}))
+                .await;
+        }
+    }
+
+    #[tokio::test]
+    async fn blocks() {
+        let cli = TestClient::new(day_two());
+
+        let resp = cli.get("/dest?from=10.0.0.0/24&key=1.2.3.255").send().await;
+        resp.assert_status_is_ok();
+        resp.assert_json(json!({
+            "network": "11.2.3.0/24",
+            "count": 256,
+            "ranges": [{"start": "11.2.3.0", "end": "11.2.3.255"}],
+        }))
+        .await;
+
+        let resp = cli.get("/dest?from=10.0.16.0/20&key=1.2.16.7").send().await;
+        resp.assert_status_is_ok();
+        resp.assert_json(json!({
+            "network": "11.2.32.0/20",
+            "count": 4096,
+            "ranges": [{"start": "11.2.32.0", "end": "11.2.47.255"}],
+        }))
+        .await;
+
+        // 250 to 265 in the third octet wraps past 255
+        let resp = cli.get("/dest?from=10.0.0.0/20&key=1.2.250.0").send().await;
+        resp.assert_status_is_ok();
+        resp.assert_json(json!({
+            "network": null,
+            "count": 4096,
+            "ranges": [
+                {"start": "11.2.0.0", "end": "11.2.9.255"},
+                {"start": "11.2.250.0", "end": "11.2.255.255"},
+            ],
+        }))
+        .await;
+
+        let resp = cli.get("/key?from=10.0.0.0&to=11.2.3.0/24").send().await;
+        resp.assert_status_is_ok();
+        resp.assert_json(json!({
+            "network": "1.2.3.0/24",
+            "count": 256,
+            "ranges": [{"start": "1.2.3.0", "end": "1.2.3.255"}],
+        }))
+        .await;
+
+        let resp = cli
+            .get("/dest?from=10.0.0.0/24&key=1.2.3.0/24")
+            .send()
+            .await;
+        resp.assert_status(StatusCode::BAD_REQUEST);
+        resp.assert_json(
+            json!({"parameter": "key", "value": "1.2.3.0/24", "reason": "multiple_blocks"}),
+        )
+        .await;
+    }
+
+    #[tokio::test]
+    async fn batch_media_types() {
+        let cli = TestClient::new(day_two());
+        let operation =
+            json!({"op": "dest", "family": "v4", "from": "10.0.0.0", "key": "1.2.3.255"});
+
+        let resp = cli
+            .post("/batch")
+            .content_type("application/json; charset=utf-8")
+            .body(json!([operation]).to_string())
+            .send()
+            .await;
+        resp.assert_status_is_ok();
+        resp.assert_json(json!([{"address": "11.2.3.255"}])).await;
+
+        let resp = cli
+            .post("/batch")
+            .content_type("Application/X-NDJSON; charset=utf-8")
+            .body(format!("{operation}\n"))
+            .send()
+            .await;
+        resp.assert_status_is_ok();
+        resp.assert_content_type("application/x-ndjson");
+        resp.assert_text("{\"address\":\"11.2.3.255\"}\n").await;
+
+        let resp = cli
+            .post("/batch")
+            .content_type("text/plain; charset=utf-8")
+            .body(json!([operation]).to_string())
+            .send()
+            .await;
+        resp.assert_status(StatusCode::UNSUPPORTED_MEDIA_TYPE);
+    }
+
+    #[tokio::test]
+    async fn mixed_batch() {
+        let cli = TestClient::new(day_two());
+        let operations = [
+            json!({"op": "dest", "family": "v4", "from": "10.0.0.0", "key": "1.2.3.255"}),
+            json!({"op": "key", "family": "v4", "from": "10.0.0.256", "to": "11.2.3.255"}),
+            json!({"op": "key", "family": "v6", "from": "aaaa::aaaa", "to": "5555::5555"}),
+        ];
+        let bad_octet =
+            json!({"error": {"parameter": "from", "value": "10.0.0.256", "reason": "bad_octet"}});
+
+        let resp = cli
+            .post("/batch")
+            .content_type("application/json")
+            .body(json!(operations).to_string())
+            .send()
+            .await;
+        resp.assert_status_is_ok();
+        resp.assert_json(json!([
+            {"address": "11.2.3.255"},
+            bad_octet,
+            {"address": "ffff::ffff"},
+        ]))
+        .await;
+
+        // A line that isn't an operation only fails its own entry
+        let body = operations
+            .iter()
+            .map(Value::to_string)
+            .chain(["{\"op\": \"shift\"}".to_owned()])
+            .collect::<Vec<_>>()
+            .join("\n");
+        let resp = cli
+            .post("/batch")
+            .content_type("application/x-ndjson")
+            .body(body)
+            .send()
+            .await;
+        resp.assert_status_is_ok();
+        let outcomes = resp
+            .0
+            .into_body()
+            .into_string()
+            .await
+            .expect("Should be UTF-8")
+            .lines()
+            .map(|line| serde_json::from_str::<Value>(line).expect("Should be JSON"))
+            .collect::<Vec<_>>();
+        assert_eq!(outcomes.len(), 4);
+        assert_eq!(outcomes[0], json!({"address": "11.2.3.255"}));
+        assert_eq!(outcomes[1], bad_octet);
+        assert_eq!(outcomes[2], json!({"address": "ffff::ffff"}));
+        assert!(outcomes[3]["error"].is_string());
+    }
+
+    #[tokio::test]
+    async fn bad_input() {
+        let cli = TestClient::new(day_two());
+
+        let resp = cli.get("/dest?from=10.0.0.256&key=1.2.3.255").send().await;
+        resp.assert_status(StatusCode::BAD_REQUEST);
+        resp.assert_json(
+            json!({"parameter": "from", "value": "10.0.0.256", "reason": "bad_octet"}),
+        )
+        .await;
+
+        let resp = cli.get("/v6/key?from=::1&to=1.2.3.4").send().await;
+        resp.assert_status(StatusCode::BAD_REQUEST);
+        resp.assert_json(json!({"parameter": "to", "value": "1.2.3.4", "reason": "wrong_family"}))
+            .await;
+    }
 }